@@ -6,6 +6,7 @@ use super::regex;
 
 use nodes::toml;
 use nodes::pattern;
+use nodes::index;
 use nodes::toml::ValueImpl;
 
 use std::io;
@@ -14,7 +15,6 @@ use std::fs;
 use std::cmp;
 use std::process;
 
-use std::io::BufReader;
 use std::path::PathBuf;
 use std::path::Path;
 use std::fs::File;
@@ -34,7 +34,15 @@ const LS_COUNT_DEFAULT: usize = 30;
 pub fn create(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
     {
         let node_type = args.value_of("type").unwrap_or(DEFAULT_NODE_TYPE);
-        let node = storage.next_node();
+        let next = match storage.reserve() {
+            Ok(a) => a,
+            Err(err) => {
+                println!("Failed to reserve node id: {}", err);
+                return -8;
+            },
+        };
+
+        let node = next.node();
 
         let mut meta = toml::Value::new();
 
@@ -49,6 +57,32 @@ pub fn create(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
             meta.set("tags", toml_tags);
         }
 
+        // fields set using their alias as flag
+        for (alias, field) in storage.config().aliases() {
+            let values = match args.values_of(alias.as_str()) {
+                Some(a) => a.map(parse_value).collect::<Vec<_>>(),
+                None => continue,
+            };
+
+            // toml arrays can't contain values of different types
+            if values.iter().any(|v| !v.same_type(&values[0])) {
+                println!("Values for '{}' (alias '{}') differ in type",
+                    field, alias);
+                return -6;
+            }
+
+            let value = if values.len() == 1 {
+                values.into_iter().next().unwrap()
+            } else {
+                toml::Value::Array(values)
+            };
+
+            if !meta.set(&field, value) {
+                println!("Cannot set field '{}' (alias '{}')", field, alias);
+                return -6;
+            }
+        }
+
         if let Some(val) = args.value_of("meta") {
             let mut val = val.replace(";", "\n");
             if let Err(err) = parse_meta(&val, &mut meta) {
@@ -63,9 +97,7 @@ pub fn create(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
                 return -1;
             }
 
-            let res = File::create(node.node_path())
-                .and_then(|mut f| f.write_all(content.as_bytes()));
-            if let Err(err) = res {
+            if let Err(err) = next.write_content(content.as_bytes()) {
                 println!("Failed to write node: {}", err);
                 return -2
             }
@@ -97,40 +129,69 @@ pub fn create(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
         meta.set("created", toml::Value::from(now.clone()));
         meta.set("type", toml::Value::from(node_type.clone()));
 
-        if let Err(err) = meta.save(node.meta_path()) {
+        if let Err(err) = next.write_meta(&meta) {
             println!("Failed to save node meta file: {}", err);
-            fs::remove_file(node.node_path())
-                .expect("Failed to removed node file");
             return -7;
         }
 
+        let node = match next.commit() {
+            Ok(a) => a,
+            Err(err) => {
+                println!("Failed to create node: {}", err);
+                return -9;
+            },
+        };
+
+        update_index(storage, &node);
         println!("Created Node {}", node.id());
     }
 
-    storage.use_id();
     0
 }
 
 // arg reverse: whether to invert the meaning of the reverse flag
 // arg reverse_list: whether to invert the meaning of the reverse_list flag
-fn list<'a, 'b>(storage: &'a nodes::Storage, args: &clap::ArgMatches,
+// The config of the first storage is used since all storages share it.
+fn list<'a>(storages: &'a [nodes::Storage<'a>], args: &clap::ArgMatches,
         reverse: bool, reverse_list: bool) -> Option<Vec<nodes::Node<'a, 'a>>> {
+    let config = match storages.first() {
+        Some(a) => a.config(),
+        None => return Some(Vec::new()),
+    };
     let tree = match args.value_of("pattern") {
-        Some(p) => match pattern::parse_condition(p) {
-            Ok(a) => Some(a),
+        Some(p) => match pattern::parse_condition_with(p,
+                &config.definitions()) {
+            Ok(a) => Some(pattern::plan(a)),
             Err(err) => {
                 println!("Could not parse condition pattern: {}", err);
+                println!("{}", err.render());
                 return None;
             },
         }
         None => None
     };
 
+    // explicit sort order or the default one from the config
+    let sort = args.value_of("sort").map(|s| s.to_string()).or_else(||
+        config.value().as_ref()
+            .and_then(|c| c.find("ls_sort"))
+            .and_then(|v| v.as_str()).map(|v| v.to_string()));
+    let sort = match sort {
+        Some(s) => match pattern::parse_sort(&s,
+                &config.definitions()) {
+            Ok(a) => a,
+            Err(err) => {
+                println!("Could not parse sort order: {}", err);
+                println!("{}", err.render());
+                return None;
+            },
+        }, None => Vec::new(),
+    };
+
     // debug argument
     if args.is_present("debug_condition") {
         if let &Some(ref tree) = &tree {
-            pattern::print_cond(tree);
-            println!("");
+            println!("{}", tree);
         }
     }
 
@@ -138,45 +199,35 @@ fn list<'a, 'b>(storage: &'a nodes::Storage, args: &clap::ArgMatches,
     let num = if args.is_present("num") {
         value_t!(args, "num", usize).unwrap_or_else(|e| e.exit())
     } else {
-        storage.config().value().as_ref()
+        config.value().as_ref()
             .and_then(|c| c.find("ls_count"))
             .and_then(|v| v.as_integer()).map(|v| v as usize)
             .unwrap_or(LS_COUNT_DEFAULT)
     };
 
-    let mut nodes: Vec<nodes::Node> = Vec::new();
-    let list = if archived { storage.archived() } else { storage.nodes() };
-    for node in list {
-        let mut meta = match node.load_meta() {
-            Ok(a) => a,
-            Err(e) => {
-                println!("Failed to load meta file for node {}: {:?}",
-                    node.id(), e);
-                continue;
-            }
-        };
-
-        // check condition
-        if let &Some(ref tree) = &tree {
-            let meta_node = pattern::MetaNode{
-                node: &node,
-                meta: &meta,
-            };
-            if !pattern::node_matches(&meta_node, &tree) {
-                continue;
-            }
-        }
-
-        nodes.push(node);
-    }
-
-    nodes.sort_by_key(|v| v.id());
-    if reverse ^ !args.is_present("reverse") {
-        nodes.reverse();
-    }
+    let offset = if args.is_present("page") {
+        let page = value_t!(args, "page", usize).unwrap_or_else(|e| e.exit());
+        page.saturating_sub(1) * num
+    } else if args.is_present("offset") {
+        value_t!(args, "offset", usize).unwrap_or_else(|e| e.exit())
+    } else {
+        0
+    };
 
-    nodes.truncate(num);
+    // Without a sort order the last (newest) nodes are shown, with one
+    // the limit applies to the head of the requested order.
+    let head = !sort.is_empty();
+    let query = nodes::Query {
+        cond: tree,
+        archived,
+        sort,
+        rank: args.is_present("rank"),
+        reverse: reverse ^ head ^ !args.is_present("reverse"),
+        offset,
+        limit: Some(num),
+    };
 
+    let mut nodes = nodes::query_all(storages, &query).nodes;
     if reverse_list ^ !args.is_present("reverse_list") {
         nodes.reverse();
     }
@@ -184,13 +235,27 @@ fn list<'a, 'b>(storage: &'a nodes::Storage, args: &clap::ArgMatches,
     Some(nodes)
 }
 
-pub fn ls(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
+// Updates the node in the content index of the given storage (if there
+// is one) after it was created or changed.
+fn update_index(storage: &nodes::Storage, node: &nodes::Node) {
+    let res = index::ContentIndex::update_existing(storage,
+        |index| index.update(node));
+    if let Err(e) = res {
+        println!("Failed to update content index for node {}: {}",
+            node.id(), e);
+    }
+}
+
+// If qualified is set, nodes are listed with the name of their storage,
+// i.e. when multiple storages were requested.
+pub fn ls(storages: &[nodes::Storage], args: &clap::ArgMatches,
+        qualified: bool) -> i32 {
     let mut lines = value_t!(args, "lines", u64).unwrap_or(1);
     if args.is_present("full") {
         lines = 99999; // TODO, we can do better than this!
     }
 
-    let nodes = match list(storage, args, false, false) {
+    let nodes = match list(storages, args, false, false) {
         Some(n) => n,
         None => {
             return -1;
@@ -198,7 +263,7 @@ pub fn ls(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
     };
 
     for node in nodes {
-        list_node(&node, lines);
+        list_node(&node, lines, qualified);
     }
 
     0
@@ -229,10 +294,10 @@ pub fn edit(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
             }
         }
 
-        let meta = match node.load_meta() {
+        let meta = match node.meta() {
             Ok(a) => a,
             Err(e) => {
-                println!("Failed to load meta for node {}: {:?}", node.id(), e);
+                println!("Failed to load meta for node {}: {}", node.id(), e);
                 return -4;
             },
         };
@@ -252,6 +317,8 @@ pub fn edit(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
                 }
             }
         };
+
+        update_index(storage, &node);
     }
 
     storage.edited(id);
@@ -269,14 +336,54 @@ pub fn config(config: &nodes::Config, _args: &clap::ArgMatches) -> i32 {
     }
 }
 
+pub fn init(config: &mut nodes::Config, args: &clap::ArgMatches) -> i32 {
+    let path = match args.value_of("path") {
+        Some(a) => PathBuf::from(a),
+        None => config.local_storage_path(),
+    };
+
+    if let Some(name) = args.value_of("name") {
+        if config.storage_names().contains(&name) {
+            println!("There already is a storage named '{}'", name);
+            return -3;
+        }
+    }
+
+    if let Err(e) = nodes::Storage::create(&path) {
+        println!("Failed to create storage at {}: {}", path.display(), e);
+        return -1;
+    }
+
+    println!("Initialized storage at {}", path.display());
+    let name = match args.value_of("name") {
+        Some(a) => a,
+        None => return 0,
+    };
+
+    let path = match path.canonicalize() {
+        Ok(a) => a,
+        Err(e) => {
+            println!("Failed to canonicalize {}: {}", path.display(), e);
+            return -4;
+        },
+    };
+
+    if let Err(e) = config.add_storage(name, path) {
+        println!("Failed to register storage as '{}': {:?}", name, e);
+        return -2;
+    }
+
+    0
+}
+
 // helper function that applies the given function on all the ids
 // passed in the given argument (if present) or otherwise over stdin.
 // If operating on stdin, returns the number of invalid lines, otherwise 0.
 // The passed function will be called for every node and must return
 // whether it was succesful.
-pub fn operate_ids_stdin<F: Fn(&mut nodes::Node) -> bool>(
+pub fn operate_ids_stdin<F: FnMut(&mut nodes::Node) -> bool>(
         storage: &nodes::Storage, args: &clap::ArgMatches,
-        argname: &str, op: F) -> i32 {
+        argname: &str, mut op: F) -> i32 {
 
     let mut res = 0;
     if args.is_present(argname) {
@@ -324,13 +431,28 @@ pub fn operate_ids_stdin<F: Fn(&mut nodes::Node) -> bool>(
 }
 
 pub fn rm(storage: &nodes::Storage, args: &clap::ArgMatches) -> i32 {
-    operate_ids_stdin(storage, args, "id", |node: &mut nodes::Node| -> bool {
+    let mut removed = Vec::new();
+    let res = operate_ids_stdin(storage, args, "id",
+            |node: &mut nodes::Node| -> bool {
         if let Err(e) = node.remove() {
-            println!("Failed to remove node {}: {}", node.id(), e);
+            println!("Failed to remove node: {}", e);
             return false
         }
+        removed.push(node.id());
         true
-    })
+    });
+
+    let ires = index::ContentIndex::update_existing(storage, |index| {
+        for id in removed {
+            index.remove(id);
+        }
+        Ok(())
+    });
+    if let Err(e) = ires {
+        println!("Failed to update content index: {}", e);
+    }
+
+    res
 }
 
 pub fn ref_path(config: &nodes::Config, args: &clap::ArgMatches) -> i32 {
@@ -348,7 +470,7 @@ pub fn ref_path(config: &nodes::Config, args: &clap::ArgMatches) -> i32 {
         Some(a) => match config.load_storage(a) {
             Ok(a) => a,
             Err(e) => {
-                println!("Failed to load storage {}: {:?}", a, e);
+                println!("{}", e);
                 return -4;
             },
         }, None => {
@@ -384,13 +506,19 @@ pub fn ref_path(config: &nodes::Config, args: &clap::ArgMatches) -> i32 {
 pub fn add(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
     {
         let node_type = DEFAULT_NODE_TYPE;
-        let node = storage.next_node();
+        let next = match storage.reserve() {
+            Ok(a) => a,
+            Err(err) => {
+                println!("Failed to reserve node id: {}", err);
+                return -3;
+            },
+        };
 
         // copy file
         let fname = args.value_of("file").
             expect("No file argument given, although it is required");
         let path = Path::new(fname);
-        if let Err(e) = fs::copy(path, node.node_path()) {
+        if let Err(e) = fs::copy(path, next.node().node_path()) {
             println!("Could not copy file to node {}: {}", fname, e);
             return -1;
         }
@@ -400,25 +528,42 @@ pub fn add(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
         meta.set("created", toml::Value::from(now.clone()));
         meta.set("type", toml::Value::from(node_type.clone()));
 
-        meta.save(node.meta_path()).expect("Failed to save node meta file");
-        if let Err(err) = meta.save(node.meta_path()) {
+        if let Err(err) = next.write_meta(&meta) {
             println!("Failed to save node meta file: {}", err);
-            fs::remove_file(node.node_path())
-                .expect("Failed to remove node file");
             return -2;
         }
 
+        let node = match next.commit() {
+            Ok(a) => a,
+            Err(err) => {
+                println!("Failed to create node: {}", err);
+                return -4;
+            },
+        };
+
+        update_index(storage, &node);
         println!("Created Node {}", node.id());
     }
 
-    storage.use_id();
     0
 }
 
 pub fn archive(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
+    // the content index is keyed by id only, so (un)archiving
+    // a node doesn't affect it
+    let state = if args.is_present("on") {
+        Some(true)
+    } else if args.is_present("off") {
+        Some(false)
+    } else {
+        None
+    };
+
+    let reason = args.value_of("reason");
     operate_ids_stdin(storage, args, "id", |node: &mut nodes::Node| -> bool {
-        if let Err(e) = node.toggle_archive() {
-            println!("Failed to (un)archive node {}: {}", node.id(), e);
+        let archive = state.unwrap_or(!node.archived());
+        if let Err(e) = node.archive_with_reason(archive, reason) {
+            println!("Failed to (un)archive node: {}", e);
             return false;
         }
         true
@@ -521,7 +666,7 @@ fn patch_program(node: &nodes::Node, prog: &mut Vec<String>) -> bool {
     }
 
     // TODO: signal error on return? don't just continue
-    // TODO: performance: don't load content multiple times, cache meta?
+    // TODO: performance: don't load content multiple times
 
     let mut used = false;
     let mut cpy = String::new();
@@ -536,17 +681,18 @@ fn patch_program(node: &nodes::Node, prog: &mut Vec<String>) -> bool {
                 cpy.drain(first.start()..first.end());
                 match first.as_str() {
                     "full_content" => {
-                        let mut s = String::new();
-                        let res = File::open(node.node_path())
-                            .and_then(|mut f| f.read_to_string(&mut s));
-                        if let Err(e) = res {
-                            println!("Failed to read '{}': {}", node.id(), e);
-                            continue;
-                        }
+                        let s = match node.read_content() {
+                            Ok(a) => a,
+                            Err(e) => {
+                                println!("Failed to read '{}': {}",
+                                    node.id(), e);
+                                continue;
+                            },
+                        };
 
                         cpy.insert_str(first.start(), &s);
                     }, "first_line" => {
-                        let f = match File::open(node.node_path()) {
+                        let reader = match node.content_reader() {
                             Ok(a) => a,
                             Err(e) => {
                                 println!("Failed to open '{}': {}",
@@ -555,7 +701,6 @@ fn patch_program(node: &nodes::Node, prog: &mut Vec<String>) -> bool {
                             },
                         };
 
-                        let mut reader = BufReader::new(f);
                         let line = match reader.lines().next() {
                             Some(Ok(a)) => a,
                             Some(Err(e)) => {
@@ -583,7 +728,15 @@ fn patch_program(node: &nodes::Node, prog: &mut Vec<String>) -> bool {
                     }, _ => {
                         if first.as_str().starts_with("meta") {
                             let entry = capture.get(2).unwrap().as_str();
-                            let meta = node.load_meta().unwrap();
+                            let meta = match node.meta() {
+                                Ok(a) => a,
+                                Err(e) => {
+                                    println!("Failed to load meta of '{}': \
+                                        {}", node.id(), e);
+                                    continue;
+                                },
+                            };
+
                             let s = meta.find(entry).and_then(|e|
                                     toml::ser::to_string_pretty(&e).ok())
                                 .unwrap_or("".to_string());
@@ -632,15 +785,25 @@ fn spawn_meta(node: &nodes::Node)
     process::Command::new(&prog[0]).args(prog[1..].iter()).status()
 }
 
-fn list_node(node: &nodes::Node, lines: u64) {
+// Returns how the given node is referred to in listings. When listing
+// nodes of multiple storages, the reference including the storage is used.
+fn node_name(node: &nodes::Node, qualified: bool) -> String {
+    if qualified {
+        node.reference()
+    } else {
+        node.id().to_string()
+    }
+}
+
+fn list_node(node: &nodes::Node, lines: u64, qualified: bool) {
     // TODO: use terminal width
-    let summary = node_summary(&node.node_path(), lines, SUMMARY_SIZE);
+    let summary = node_summary(node, lines, SUMMARY_SIZE);
 
     if lines == 1 {
         println!("{}:\t{:<w$}",
-            node.id(), summary, w = SUMMARY_SIZE);
+            node_name(node, qualified), summary, w = SUMMARY_SIZE);
     } else {
-        println!("{}:", node.id());
+        println!("{}:", node_name(node, qualified));
         for line in summary.lines() {
             println!("\t{}", line);
         }
@@ -649,11 +812,11 @@ fn list_node(node: &nodes::Node, lines: u64) {
 }
 
 
-fn node_summary(path: &PathBuf, lines: u64, width: usize) -> String {
+fn node_summary(node: &nodes::Node, lines: u64, width: usize) -> String {
     if lines == 1 {
-        short_string(&read_node(&path, lines, false), width)
+        short_string(&read_node(node, lines, false), width)
     } else {
-        read_node(&path, lines, true)
+        read_node(node, lines, true)
     }
 }
 
@@ -680,13 +843,12 @@ fn short_string(lstr: &str, max_length: usize) -> String {
     s
 }
 
-fn read_node(path: &PathBuf, mut lines: u64, dot: bool) -> String {
-    let f = match File::open(path) {
+fn read_node(node: &nodes::Node, mut lines: u64, dot: bool) -> String {
+    let f = match node.content_reader() {
         Ok(v) => v,
         Err(_) => return "<Invalid node>".to_string(),
     };
 
-    let f = BufReader::new(&f);
     let mut ret = String::new();
 
     for line in f.lines() {
@@ -804,6 +966,34 @@ fn parse_meta(s: &str, val: &mut toml::Value) -> Result<(), String> {
     return append_toml(val, &parsed);
 }
 
+// Parses the given string as scalar toml value, e.g. '5', 'true' or
+// '1979-05-27'. Falls back to a plain string otherwise, quotes can be
+// used to force a string. Arrays are built by repeating the flag.
+fn parse_value(s: &str) -> toml::Value {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return toml::Value::from(&s[1..s.len() - 1]);
+    }
+
+    if let Ok(b) = s.parse::<bool>() {
+        return toml::Value::Boolean(b);
+    }
+
+    if let Ok(i) = s.parse::<i64>() {
+        return toml::Value::Integer(i);
+    }
+
+    // rust also parses things like 'inf' or 'NaN' as float
+    let numeric = s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+    if let (true, Ok(f)) = (numeric, s.parse::<f64>()) {
+        return toml::Value::Float(f);
+    }
+
+    match s.parse::<toml::value::Datetime>() {
+        Ok(d) => toml::Value::Datetime(d),
+        Err(_) => toml::Value::from(s),
+    }
+}
+
 // Tries to appends the toml stored in src to the toml stored in dst.
 // Will fail if there are duplicates, but append to arrays and insert
 // into tables (recursively).
@@ -859,47 +1049,39 @@ fn strip_node_meta(node: &nodes::Node, meta: &mut toml::Value) {
 
     // check if we can read the first line, in which case
     // we will check (and strip) it for metadata annotations
-    let mut data = Vec::new();
-
-    {
-        let file = match File::open(node.node_path()) {
-            Ok(a) => a,
-            Err(e) => {
-                println!("Failed to open created node: {}", e);
-                return;
-            },
-        };
-
-        // TODO: read all lines until one without "nodes: " comes?
-        let reader = BufReader::new(&file);
-        let mut lines = reader.lines();
-        if let Some(Ok(mut line)) = lines.next() {
-            if line.starts_with("nodes: ") {
-                line.drain(0..7);
-                line = line.replace(";", "\n");
-                if let Err(err) = parse_meta(&line, meta) {
-                    println!("Invalid node meta: {}", err);
-                    return
-                }
+    let mut data = match node.read_content_bytes() {
+        Ok(a) => a,
+        Err(e) => {
+            println!("Failed to read created node: {}", e);
+            return;
+        },
+    };
 
-                let mut reader = BufReader::new(&file);
-                reader.seek(io::SeekFrom::Start(0)).unwrap();
-                reader.read_to_end(&mut data).unwrap();
-                let idx = data.iter().position(|&v| v == '\n' as u8);
-                if let Some(first) = idx {
-                    data.drain(0..(first+1));
-                }
-            }
-        } else {
-            println!("Could not parse first line");
+    // TODO: read all lines until one without "nodes: " comes?
+    if let Some(Ok(mut line)) = data.lines().next() {
+        if !line.starts_with("nodes: ") {
             return;
         }
+
+        line.drain(0..7);
+        line = line.replace(";", "\n");
+        if let Err(err) = parse_meta(&line, meta) {
+            println!("Invalid node meta: {}", err);
+            return
+        }
+
+        let idx = data.iter().position(|&v| v == b'\n');
+        match idx {
+            Some(first) => data.drain(0..(first+1)),
+            None => data.drain(..),
+        };
+    } else {
+        println!("Could not parse first line");
+        return;
     }
 
     if !data.is_empty() {
-        let res = File::create(node.node_path())
-            .and_then(|mut f| f.write_all(&data));
-        if let Err(err) = res {
+        if let Err(err) = node.write_content(&data) {
             println!("Failed to write stripped node file: {}", err);
             return;
         }
@@ -908,6 +1090,7 @@ fn strip_node_meta(node: &nodes::Node, meta: &mut toml::Value) {
 
 struct SelectNode<'a, 'b: 'a> {
     node: nodes::Node<'a, 'b>,
+    name: String,
     summary: String,
     selected: bool,
 }
@@ -932,13 +1115,12 @@ fn write_select_list<W: Write>(screen: &mut W, nodes: &Vec<SelectNode>,
                 termion::color::Fg(termion::color::LightRed)).unwrap();
         }
 
-        let idstr = node.node.id().to_string();
         write!(screen, "{}{}: {:<w$}{}{}",
             termion::cursor::Goto(x, y),
-            idstr, node.summary,
+            node.name, node.summary,
             termion::color::Bg(termion::color::Reset),
             termion::color::Fg(termion::color::Reset),
-            w = (maxx as usize) - idstr.len() - 2).unwrap();
+            w = (maxx as usize) - node.name.len() - 2).unwrap();
 
         y += 1;
         i += 1;
@@ -946,7 +1128,9 @@ fn write_select_list<W: Write>(screen: &mut W, nodes: &Vec<SelectNode>,
 }
 
 // NOTE: experimental!
-pub fn select(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
+// See ls for qualified.
+pub fn select(storages: &[nodes::Storage], args: &clap::ArgMatches,
+        qualified: bool) -> i32 {
     // problem: when stdin isn't /dev/tty
     // let tty = fs::File::open("/dev/tty").unwrap();
     // TODO: https://github.com/redox-os/termion/blob/master/src/sys/unix/size.rs
@@ -955,7 +1139,7 @@ pub fn select(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
         _ => (80, 100) // guess
     };
 
-    let lnodes = match list(storage, args, false, true) {
+    let lnodes = match list(storages, args, false, true) {
         Some(n) => n,
         None => {
             return -1;
@@ -964,8 +1148,9 @@ pub fn select(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
 
     let mut nodes: Vec<SelectNode> = Vec::new();
     for node in lnodes {
-        let summary = node_summary(&node.node_path(), 1, maxx as usize);
+        let summary = node_summary(&node, 1, maxx as usize);
         nodes.push(SelectNode{
+            name: node_name(&node, qualified),
             node: node,
             summary: summary,
             selected: false
@@ -1097,7 +1282,7 @@ pub fn select(storage: &mut nodes::Storage, args: &clap::ArgMatches) -> i32 {
 
     for node in nodes {
         if node.selected {
-            println!("{}", node.node.id());
+            println!("{}", node.name);
         }
     }
 
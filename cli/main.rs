@@ -1,11 +1,37 @@
 #[macro_use] extern crate clap;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate log;
 extern crate nodes;
 extern crate regex;
 extern crate termion;
 
 mod commands;
 
+// names and long flags of the create arguments, aliases can't shadow them
+const CREATE_ARGS: &[&str] = &["meta", "tags", "tag", "type", "content",
+    "help", "version"];
+
+// Prints the warnings (and with -v more) logged by the library to
+// stderr, so they don't mix with output that is piped, e.g. from select.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level().to_string().to_lowercase(),
+                record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
 fn ret_main() -> i32 {
     fn is_uint(v: String) -> Result<(), String> {
         if let Err(_) = v.parse::<u64>() {
@@ -23,26 +49,63 @@ fn ret_main() -> i32 {
         is_uint(v)
     }
 
+    // warnings while loading the config are shown, verbosity is only
+    // known once the arguments are parsed
+    log::set_logger(&LOGGER).expect("Failed to set logger");
+    log::set_max_level(log::LevelFilter::Warn);
+
+    // the config is needed to build the arguments since field aliases
+    // can be used as flags for create. A broken config is only reported
+    // after parsing the arguments so that e.g. --help still works
+    let config = nodes::Config::load_default();
+    let aliases = config.as_ref().map(|c| c.aliases()).unwrap_or_default()
+        .into_iter()
+        .filter(|(alias, field)| !field.starts_with('@') &&
+            !CREATE_ARGS.contains(&alias.as_str()))
+        .map(|(alias, field)| {
+            let help = format!("Set the '{}' field, repeat for an array", field);
+            (alias, help)
+        }).collect::<Vec<_>>();
+
+    let mut create = clap_app!(create =>
+        (about: "Creates a new node")
+        (alias: "c")
+        (@arg meta: !required index(1) "Associate metadata with this node")
+        (@arg tags: -t --tag +takes_value !required ... +use_delimiter
+            "Tag the node")
+        (@arg type: --type +takes_value !required
+            "Type of node to create, will open matching editor")
+        (@arg content: -c --content +takes_value !required
+            "Write this content into the node instead of open an editor")
+    );
+
+    for (alias, help) in &aliases {
+        create = create.arg(clap::Arg::with_name(alias)
+            .long(alias)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(help));
+    }
+
     let matches = clap_app!(nodes =>
         (version: "0.1")
         (setting: clap::AppSettings::VersionlessSubcommands)
         (author: "nyorain [at gmail dot com]")
         (about: "Manages your node system from the command line")
-        (@arg storage: -s --storage +takes_value "The storage to use")
+        (@arg storage: -s --storage +takes_value +use_delimiter +global
+            "The storage to use. ls and select accept multiple ones")
         (@arg local: -l --local
             conflicts_with("storage")
             "Search for a local node storage in current directory")
-        (@subcommand create =>
-            (about: "Creates a new node")
-            (alias: "c")
-            (@arg meta: !required index(1) "Associate metadata with this node")
-            (@arg tags: -t --tag +takes_value !required ... +use_delimiter
-                "Tag the node")
-            (@arg type: --type +takes_value !required
-                "Type of node to create, will open matching editor")
-            (@arg content: -c --content +takes_value !required
-                "Write this content into the node instead of open an editor")
-        ) (@subcommand rm =>
+        (@arg all_storages: -A --("all-storages") +global
+            conflicts_with[storage local]
+            "Use all configured storages, only for ls and select")
+        (@arg verbose: -v --verbose +multiple
+            "Show more diagnostics on stderr, repeat for even more")
+        (@arg quiet: -q --quiet conflicts_with("verbose")
+            "Only show errors, no warnings")
+        (@subcommand rm =>
             (about: "Removes a node (by id)")
             (@arg id: +multiple index(1) {is_node}
                 "The nodes id. Can also specify multiple nodes.
@@ -70,6 +133,15 @@ fn ret_main() -> i32 {
                 "Reverses the display order")
             (@arg archived: -a !takes_value !required
                 "Show only archived nodes")
+            (@arg rank: --rank !takes_value !required
+                "Order by how well nodes match content and fuzzy queries")
+            (@arg offset: --offset +takes_value {is_uint}
+                "Skip this many nodes")
+            (@arg page: --page +takes_value {is_uint} conflicts_with("offset")
+                "Show this page (starting at 1), pages have --num nodes")
+            (@arg sort: --sort +takes_value +allow_hyphen_values
+                "Sort by these comma-separated fields, '-' for descending. \
+                The first nodes in this order are shown")
             (@arg debug_condition: -d !takes_value !required +hidden
                 "Debug the condition tree")
         ) (@subcommand show =>
@@ -88,12 +160,27 @@ fn ret_main() -> i32 {
                 "Origin node path. Needed for 'this' storage")
            (about: "Resolves a node reference to a path")
         ) (@subcommand archive =>
-            (about: "Toggles archived state of node")
+            (about: "Toggles (or sets) archived state of nodes")
             (@arg id: +multiple index(1) {is_node}
                 "Id of node to archive. Can also specify multiple nodes.
                 If not given, will read from stdin")
+            (@arg on: --on conflicts_with("off")
+                "Archive the nodes instead of toggling")
+            (@arg off: --off
+                "Unarchive the nodes instead of toggling")
+            (@arg reason: -r --reason +takes_value conflicts_with("off")
+                "Record why the nodes were archived")
         ) (@subcommand config =>
             (about: "Edit config file")
+        ) (@subcommand init =>
+            (about: "Creates a new storage")
+            (@arg path: index(1) required_unless("local")
+                conflicts_with("local")
+                "Where to create the storage")
+            (@arg local: -l --local
+                "Create a local storage in the current directory")
+            (@arg name: -n --name +takes_value
+                "Register the storage in the config under this name")
         ) (@subcommand select =>
             (about: "Select a list of nodes, ids will be printed to stdout")
             (@arg pattern: index(1)
@@ -104,6 +191,15 @@ fn ret_main() -> i32 {
                 "Maximum number of nodes to show")
             (@arg archived: -a !takes_value !required
                 "Show only archived nodes")
+            (@arg rank: --rank !takes_value !required
+                "Order by how well nodes match content and fuzzy queries")
+            (@arg offset: --offset +takes_value {is_uint}
+                "Skip this many nodes")
+            (@arg page: --page +takes_value {is_uint} conflicts_with("offset")
+                "Show this page (starting at 1), pages have --num nodes")
+            (@arg sort: --sort +takes_value +allow_hyphen_values
+                "Sort by these comma-separated fields, '-' for descending. \
+                The first nodes in this order are shown")
             (@arg debug_condition: -d !takes_value !required +hidden
                 "Debug the condition tree")
             (@arg reverse: -R --rev !takes_value !required
@@ -112,49 +208,119 @@ fn ret_main() -> i32 {
                 "Reverses the display order")
 
         )
-    ).get_matches();
+    ).subcommand(create).get_matches();
+
+    log::set_max_level(if matches.is_present("quiet") {
+        log::LevelFilter::Error
+    } else {
+        match matches.occurrences_of("verbose") {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    });
+
+    let mut config = match config {
+        Ok(a) => a,
+        Err(e) => {
+            println!("Error loading config: {:?}", e);
+            return -1;
+        },
+    };
 
-    // load config & match storage-independent commands
-    let config = nodes::Config::load_default().expect("Error loading config");
+    // match storage-independent commands
     match matches.subcommand() {
         ("config", Some(s)) => return commands::config(&config, s),
         ("ref", Some(s)) => return commands::ref_path(&config, s),
+        ("init", Some(s)) => return commands::init(&mut config, s),
         _ => {},
     }
 
-    // storage-dependent commands, load a storage
-    let mut storage = match if matches.is_present("local") {
-        config.load_local_storage()
+    // storage-dependent commands, load the storages
+    // storage args are global, they might be given after the subcommand
+    let storage_args = match matches.subcommand() {
+        (_, Some(s)) if s.is_present("storage") ||
+            s.is_present("all_storages") => s,
+        _ => &matches,
+    };
+
+    let all = storage_args.is_present("all_storages");
+    let loaded = if matches.is_present("local") {
+        vec!(config.load_local_storage())
+    } else if all {
+        config.storages().map(|(_, storage)| storage).collect()
     } else {
-        match matches.value_of("storage") {
-            Some(name) => config.load_storage(name),
-            None => config.load_default_storage(),
+        match storage_args.values_of("storage") {
+            Some(names) => names.map(|n| config.load_storage(n)).collect(),
+            None => vec!(config.load_default_storage()),
         }
-    } {
-        Ok(a) => a,
-        Err(e) => {
-            println!("Error fetching storage: {:?}", e);
-            return 1;
-        },
     };
 
+    // when using multiple storages, the ones that fail to load are
+    // skipped so they don't prevent querying the others. Whether node
+    // references are qualified with their storage only depends on
+    // the requested storages, not on the ones that could be loaded
+    let multiple = all || loaded.len() > 1;
+    let mut storages = Vec::new();
+    for storage in loaded {
+        let err = match storage {
+            Ok(a) => {
+                storages.push(a);
+                continue;
+            }, Err(e) => e,
+        };
+
+        if !multiple {
+            println!("{}", err);
+            return 1;
+        }
+
+        warn!("Skipping storage: {}", err);
+    }
+
+    if storages.is_empty() {
+        println!("None of the storages could be loaded");
+        return 1;
+    }
+
+    // commands that can work on multiple storages
     match matches.subcommand() {
+        ("ls", Some(s)) => return commands::ls(&storages, s, multiple),
+        ("select", Some(s)) =>
+            return commands::select(&storages, s, multiple),
+        // TODO: default action when just a node id is given
+        // e.g. nodes 234 should show/edit that node
+        (_, None) => return commands::ls(&storages,
+            &clap::ArgMatches::default(), multiple),
+        _ => {},
+    }
+
+    if storages.len() != 1 {
+        println!("Only ls and select support multiple storages");
+        return 2;
+    }
+
+    let mut storage = storages.pop().unwrap();
+    let res = match matches.subcommand() {
         ("rm", Some(s)) => commands::rm(&mut storage, s),
         ("edit", Some(s)) => commands::edit(&mut storage, s),
         ("create", Some(s)) => commands::create(&mut storage, s),
         ("add", Some(s)) => commands::add(&mut storage, s),
-        ("ls", Some(s)) => commands::ls(&mut storage, s),
         ("archive", Some(s)) => commands::archive(&mut storage, s),
-        ("select", Some(s)) => commands::select(&mut storage, s),
         ("show", Some(s)) => commands::show(&mut storage, s),
-        (_, Some(_)) => {
+        _ => {
             println!("Currently not supported");
-            return 2;
+            2
         },
-        // TODO: default action when just a node id is given
-        // e.g. nodes 234 should show/edit that node
-        _ => commands::ls(&mut storage, &clap::ArgMatches::default())
+    };
+
+    if let Err(e) = storage.save() {
+        println!("Failed to save storage state: {}", e);
+        return -10;
     }
+
+    res
 }
 
 fn main() {
@@ -6,7 +6,7 @@ use std::fs::File;
 
 fn output<'a, 'b>(storage: &'a nodes::Storage, archived: bool) {
     let list = if archived { storage.archived() } else { storage.nodes() };
-    for node in list {
+    for node in list.filter_map(Result::ok) {
         let mut meta = match node.load_meta() {
             Ok(a) => a,
             Err(e) => {
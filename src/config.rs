@@ -1,12 +1,14 @@
 use super::toml;
 use super::storage;
+use super::pattern;
+use super::error::Error;
+use super::file;
 
 use std::io;
 use std::env;
 use std::fs;
+use std::vec;
 
-use std::fs::File;
-use std::io::prelude::*;
 use std::path::PathBuf;
 use std::collections::HashSet;
 use std::collections::HashMap;
@@ -23,6 +25,12 @@ pub struct Config {
     storage: StorageConfig
 }
 
+/// Iterator over all configured storages, see Config::storages.
+pub struct Storages<'a> {
+    config: &'a Config,
+    names: vec::IntoIter<&'a str>,
+}
+
 #[derive(Deserialize, Serialize)]
 struct ParseStorage {
     name: String,
@@ -39,12 +47,16 @@ struct ParseStorageConfig {
 #[derive(Debug)]
 pub enum ConfigError {
     Read(io::Error),
+    Write(io::Error),
     Parse(toml::de::Error),
     InvalidStorage,
     NoStorage,
     NoStorages,
     RedundantStorages,
-    InvalidDefaultStorage
+    InvalidDefaultStorage,
+    /// A saved query has the name of a pseudo field, which it would
+    /// be shadowed by. Contains the name.
+    ReservedQueryName(String),
 }
 
 impl Config {
@@ -70,26 +82,107 @@ impl Config {
             },
         };
 
-        Ok(Config{value: Some(value), storage})
+        let config = Config{value: Some(value), storage};
+        if let Some(name) = config.queries().into_keys()
+                .find(|n| pattern::PSEUDO_FIELDS.contains(&n.as_str())) {
+            return Err(ConfigError::ReservedQueryName(name));
+        }
+
+        Ok(config)
     }
 
     /// Tries to load the storage with the given name.
-    /// Will return None if there is no such storage or it cannot be loaded.
+    /// Fails with LoadStorageError::InvalidName if there is no such
+    /// storage.
     /// Storages are lazily loaded/parsed and not cached so the caller
     /// should cache it if needed multiple times.
-    pub fn load_storage(&self, name: &str) 
-            -> Result<storage::Storage, storage::LoadStorageError> {
+    pub fn load_storage(&self, name: &str)
+            -> Result<storage::Storage, Error> {
         let path = match self.storage.storages.get(name) {
             Some(a) => a.clone(),
-            None => return Err(storage::LoadStorageError::InvalidName),
+            None => return Err(Error::LoadStorage {
+                storage: name.to_string(),
+                cause: storage::LoadStorageError::InvalidName,
+            }),
         };
         
         storage::Storage::load(self, name, path)
     }
 
+    /// Registers the storage at the given path under the given name
+    /// and writes the config file. If there was none, the storages
+    /// used so far (i.e. the default storage) are kept in it.
+    /// An existing config file is only appended to, so its comments
+    /// and formatting are kept. Only if the new entry can't be appended
+    /// (e.g. because the storages are given as inline array) the whole
+    /// file is formatted again.
+    /// Fails with RedundantStorages if there already is a storage with
+    /// this name or path.
+    pub fn add_storage(&mut self, name: &str, path: PathBuf)
+            -> Result<(), ConfigError> {
+        if self.storage.storages.contains_key(name) ||
+                self.storage.storages.values().any(|p| *p == path) {
+            return Err(ConfigError::RedundantStorages);
+        }
+
+        let entry = ParseStorage { name: name.to_string(), path: path.clone() };
+        let (value, doc) = match self.value.clone() {
+            Some(mut value) => {
+                push_storage(&mut value, &entry);
+                let doc = fs::read_to_string(Config::config_path())
+                    .map_err(ConfigError::Read)?;
+                let doc = append_storage(&doc, &entry, &value)
+                    .unwrap_or_else(|| {
+                        warn!("Could not append storage to {}, formatting \
+                            it again", Config::config_path().display());
+                        format_config(&value)
+                    });
+                (value, doc)
+            }, None => {
+                let mut value = toml::Value::new();
+                value.set("storage.default", self.storage.default.clone());
+                value.set("storage.storages", toml::Value::Array(Vec::new()));
+                for name in self.storage_names() {
+                    let path = self.storage.storages[name].clone();
+                    let entry = ParseStorage { name: name.to_string(), path };
+                    push_storage(&mut value, &entry);
+                }
+
+                push_storage(&mut value, &entry);
+                let doc = format_config(&value);
+                (value, doc)
+            }
+        };
+
+        fs::create_dir_all(Config::config_folder())
+            .and_then(|_| file::write_atomic(Config::config_path(),
+                doc.as_bytes()))
+            .map_err(ConfigError::Write)?;
+
+        self.storage.storages.insert(name.to_string(), path);
+        self.value = Some(value);
+        Ok(())
+    }
+
+    /// Returns the names of all configured storages, sorted.
+    pub fn storage_names(&self) -> Vec<&str> {
+        let mut names = self.storage.storages.keys()
+            .map(|n| n.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Returns an iterator over all configured storages (sorted by name)
+    /// that loads them one after another. Yields the name of each
+    /// storage together with the result of loading it.
+    pub fn storages(&self) -> Storages {
+        Storages { config: self, names: self.storage_names().into_iter() }
+    }
+
     /// Loads the default storage.
     pub fn load_default_storage(&self)
-            -> Result<storage::Storage, storage::LoadStorageError> {
+            -> Result<storage::Storage, Error> {
         self.load_storage(&self.storage.default)
     }
 
@@ -100,20 +193,20 @@ impl Config {
     /// found node storage.
     /// Returns LoadStorageError::NotFound if none is found.
     pub fn load_local_storage(&self)
-            -> Result<storage::Storage, storage::LoadStorageError> {
+            -> Result<storage::Storage, Error> {
         let mut cwd = env::current_dir().expect("Failed to get current dir");
         'outer: loop {
             let mut npath = PathBuf::from(cwd.clone());
             for spath in &self.storage.local_search_paths {
                 npath.push(spath);
                 if !npath.is_dir() {
-                    println!("{:?} isn't a dir", npath);
+                    debug!("{:?} isn't a dir", npath);
                     continue;
                 }
 
                 npath.push("storage");
                 if !npath.is_file() {
-                    println!("{:?} isn't a file", npath);
+                    debug!("{:?} isn't a file", npath);
                     continue;
                 }
 
@@ -125,11 +218,24 @@ impl Config {
             }
 
             if !cwd.pop() {
-                return Err(storage::LoadStorageError::NotFound);
+                return Err(Error::LoadStorage {
+                    storage: "local".to_string(),
+                    cause: storage::LoadStorageError::NotFound,
+                });
             }
         }
     }
 
+    /// Returns the path at which a new local storage in the current
+    /// directory should be created, i.e. the first of the configured
+    /// local search paths.
+    pub fn local_storage_path(&self) -> PathBuf {
+        let mut path = env::current_dir().expect("Failed to get current dir");
+        path.push(self.storage.local_search_paths.first()
+            .map(|s| s.as_str()).unwrap_or(".nodes"));
+        path
+    }
+
     pub fn config_folder() -> PathBuf {
         let mut p = Config::home_dir();
         p.push(".config");
@@ -149,6 +255,33 @@ impl Config {
         &self.value
     }
 
+    /// Returns the field aliases defined in the 'patterns.aliases'
+    /// table, mapping each alias to the field name it stands for.
+    /// Entries whose value is not a string are ignored.
+    pub fn aliases(&self) -> HashMap<String, String> {
+        self.string_table("patterns.aliases")
+    }
+
+    /// Returns the saved queries defined in the 'queries' table,
+    /// mapping each name to its pattern. Names of pseudo fields are
+    /// rejected when loading the config, see ConfigError.
+    /// Entries whose value is not a string are ignored.
+    pub fn queries(&self) -> HashMap<String, String> {
+        self.string_table("queries")
+    }
+
+    /// Returns the aliases and saved queries to use when parsing patterns.
+    pub fn definitions(&self) -> pattern::Definitions {
+        pattern::Definitions {
+            aliases: self.aliases(),
+            queries: self.queries(),
+            unordered_arrays: self.value.as_ref()
+                .and_then(|v| v.find("patterns.unordered_arrays"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+        }
+    }
+
     // -- private implementation --
     fn default_config() -> Config {
         let mut storages = HashMap::new();
@@ -156,24 +289,10 @@ impl Config {
         // we make sure that the default storage exists
         // when running nodes for the first time this assures that
         // it can already be used
-        let mut storage = Config::default_storage_path();
+        let storage = Config::default_storage_path();
         if !storage.is_dir() {
-            fs::create_dir_all(&storage)
-                .expect("Failed to create default storage path");
-
-            storage.push("storage");
-            File::create(&storage)
-                .and_then(|mut f| f.write_all(b"last_id = 0"))
-                .expect("Unable to create default storage file");
-            storage.pop();
-
-            storage.push("nodes");
-            fs::create_dir_all(&storage).unwrap();
-            storage.pop();
-
-            storage.push("meta");
-            fs::create_dir_all(&storage).unwrap();
-            storage.pop();
+            storage::Storage::create(&storage)
+                .expect("Failed to create default storage");
         }
         
         storages.insert("default".to_string(), storage);
@@ -231,6 +350,20 @@ impl Config {
         Ok(StorageConfig{storages, local_search_paths, default})
     }
 
+    fn string_table(&self, name: &str) -> HashMap<String, String> {
+        let table = self.value.as_ref()
+            .and_then(|v| v.find(name))
+            .and_then(|v| v.as_table());
+        let table = match table {
+            Some(a) => a,
+            None => return HashMap::new(),
+        };
+
+        table.iter()
+            .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+            .collect()
+    }
+
     fn home_dir() -> PathBuf {
         env::home_dir().expect("Could not retrieve home directory")
     }
@@ -247,3 +380,89 @@ impl Config {
         vec!(String::from(".nodes"))
     }
 }
+
+impl<'a> Iterator for Storages<'a> {
+    type Item = (&'a str, Result<storage::Storage<'a>, Error>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let config = self.config;
+        self.names.next().map(|name| (name, config.load_storage(name)))
+    }
+}
+
+// Appends the given storage as '[[storage.storages]]' block to the given
+// config document. Returns None if the resulting document does not
+// have the expected value.
+fn append_storage(doc: &str, storage: &ParseStorage, expected: &toml::Value)
+        -> Option<String> {
+    let mut block = toml::Value::new();
+    block.set("storage.storages", toml::Value::Array(vec!(
+        toml::Value::try_from(storage).ok()?)));
+
+    let mut doc = doc.to_string();
+    if !doc.is_empty() && !doc.ends_with('\n') {
+        doc.push('\n');
+    }
+
+    doc.push('\n');
+    doc.push_str(&toml::to_string(&block).ok()?);
+    match doc.parse::<toml::Value>() {
+        Ok(ref v) if v == expected => Some(doc),
+        _ => None,
+    }
+}
+
+// Formats the given config value as document.
+fn format_config(config: &toml::Value) -> String {
+    toml::ser::to_string_pretty(config)
+        .expect("Internal error, serializing config")
+}
+
+// Appends the given storage to the storages array of the given config.
+fn push_storage(config: &mut toml::Value, storage: &ParseStorage) {
+    let entry = toml::Value::try_from(storage)
+        .expect("Internal error, serializing storage entry");
+    if let Some(toml::Value::Array(storages)) =
+            config.find_mut("storage.storages") {
+        return storages.push(entry);
+    }
+
+    config.set("storage.storages", toml::Value::Array(vec!(entry)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> ParseStorage {
+        ParseStorage { name: name.to_string(), path: PathBuf::from("/a") }
+    }
+
+    // Returns the value of the given document with the given storage
+    // added, see append_storage.
+    fn expected(doc: &str, storage: &ParseStorage) -> toml::Value {
+        let mut value = doc.parse::<toml::Value>().unwrap();
+        push_storage(&mut value, storage);
+        value
+    }
+
+    #[test]
+    fn append_keeps_comments() {
+        let doc = "# config\n[storage]\ndefault = 'n' # inline\n\
+            [[storage.storages]]\nname = 'n'\npath = '/n'\n\
+            [queries]\n# comment\ntodo = 'tags:todo'";
+        let appended = append_storage(doc, &entry("a"),
+            &expected(doc, &entry("a"))).unwrap();
+        assert!(appended.starts_with(doc));
+        assert!(appended.contains("# inline"));
+        assert!(appended.contains("# comment"));
+    }
+
+    #[test]
+    fn append_inline_array() {
+        let doc = "[storage]\ndefault = 'n'\n\
+            storages = [{ name = 'n', path = '/n' }]\n";
+        assert!(append_storage(doc, &entry("a"),
+            &expected(doc, &entry("a"))).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use super::toml;
+use super::storage::{EnumerateError, LoadStorageError};
+
+use std::io;
+use std::fmt;
+use std::error;
+use std::path::PathBuf;
+
+/// Crate-wide error type, holds the context of what failed.
+/// Returned by the operations loading or modifying storages and nodes.
+/// The library never prints, problems that don't cause an operation
+/// to fail are logged as warnings holding such an error.
+#[derive(Debug)]
+pub enum Error {
+    /// A node file could not be read or written.
+    NodeFile { id: u64, path: PathBuf, cause: io::Error },
+    /// The meta file of a node could not be loaded.
+    Meta { id: u64, path: PathBuf, cause: toml::LoadError },
+    /// Only one of node and meta file of a node exist, path is the
+    /// one that is missing.
+    Incomplete { id: u64, path: PathBuf },
+    /// A file of a storage (like its state file or a cache) could not be
+    /// accessed.
+    Storage { storage: String, path: PathBuf, cause: io::Error },
+    /// An entry of a storage could not be enumerated.
+    Enumerate { storage: String, cause: EnumerateError },
+    /// A storage could not be loaded.
+    LoadStorage { storage: String, cause: LoadStorageError },
+    /// A string could not be interpreted as node id, see Storage::parse.
+    InvalidId { storage: String, name: String },
+    /// There is no node with the given id.
+    NoNode { storage: String, id: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NodeFile { id, path, cause } =>
+                write!(f, "Node {}: failed to access '{}': {}",
+                    id, path.display(), cause),
+            Error::Meta { id, path, cause } =>
+                write!(f, "Node {}: failed to load meta file '{}': {}",
+                    id, path.display(), cause),
+            Error::Incomplete { id, path } =>
+                write!(f, "Node {}: missing '{}'", id, path.display()),
+            Error::Storage { storage, path, cause } =>
+                write!(f, "Storage {}: failed to access '{}': {}",
+                    storage, path.display(), cause),
+            Error::Enumerate { storage, cause } =>
+                write!(f, "Storage {}: {}", storage, cause),
+            Error::LoadStorage { storage, cause } =>
+                write!(f, "Storage {}: failed to load: {}", storage, cause),
+            Error::InvalidId { storage, name } =>
+                write!(f, "Storage {}: '{}' is no node id", storage, name),
+            Error::NoNode { storage, id } =>
+                write!(f, "Storage {}: node {} doesn't exist", storage, id),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::NodeFile { cause, .. } => Some(cause),
+            Error::Meta { cause, .. } => Some(cause),
+            Error::Storage { cause, .. } => Some(cause),
+            Error::Enumerate { cause, .. } => Some(cause),
+            Error::LoadStorage { cause, .. } => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for toml::LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            toml::LoadError::Open(e) => write!(f, "failed to open: {}", e),
+            toml::LoadError::Read(e) => write!(f, "failed to read: {}", e),
+            toml::LoadError::Parse(e) => write!(f, "invalid toml: {}", e),
+        }
+    }
+}
+
+impl error::Error for toml::LoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            toml::LoadError::Open(e) | toml::LoadError::Read(e) => Some(e),
+            toml::LoadError::Parse(e) => Some(e),
+        }
+    }
+}
+
+impl fmt::Display for EnumerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnumerateError::ReadDir(path, e) =>
+                write!(f, "failed to read '{}': {}", path.display(), e),
+            EnumerateError::Entry(e) =>
+                write!(f, "failed to read directory entry: {}", e),
+            EnumerateError::InvalidName(path) =>
+                write!(f, "'{}' is no node", path.display()),
+        }
+    }
+}
+
+impl error::Error for EnumerateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            EnumerateError::ReadDir(_, e) | EnumerateError::Entry(e) =>
+                Some(e),
+            EnumerateError::InvalidName(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for LoadStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadStorageError::InvalidName =>
+                write!(f, "no storage with this name is configured"),
+            LoadStorageError::NotFound => write!(f, "no storage found"),
+            LoadStorageError::Open(e) =>
+                write!(f, "failed to open state file: {}", e),
+            LoadStorageError::Read(e) =>
+                write!(f, "failed to read state file: {}", e),
+            LoadStorageError::Parse(e) =>
+                write!(f, "invalid state file: {}", e),
+        }
+    }
+}
+
+impl error::Error for LoadStorageError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoadStorageError::Open(e) | LoadStorageError::Read(e) => Some(e),
+            LoadStorageError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+/// Replaces the file at the given path with the given data.
+/// Writes a temporary file next to it first, syncs it to disk and then
+/// renames it into place, so the file either holds the old or the
+/// new data, even if we crash midway.
+pub fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp = tmp_path(path)?;
+    let res = File::create(&tmp)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return res;
+    }
+
+    // make sure the rename itself is persisted
+    // opening directories is not possible on all platforms
+    #[cfg(unix)]
+    {
+        if let Some(parent) = path.parent() {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            File::open(parent)?.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the path of the temporary file used to write the given path.
+// Contains the process id so that concurrent writers don't share it.
+fn tmp_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Invalid file path '{}'", path.display())))?;
+    Ok(path.with_file_name(format!(".{}.{}.tmp", name, process::id())))
+}
@@ -0,0 +1,545 @@
+use super::storage::Storage;
+use super::node::Node;
+use super::toml;
+use super::file;
+
+use std::io;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::cell::RefCell;
+use std::time::UNIX_EPOCH;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+const INDEX_HEADER: &str = "nodes-content-index 1";
+const META_CACHE_HEADER: &str = "nodes-meta-cache 1";
+
+/// Splits the given text into lowercase words.
+/// Everything that is not alphanumeric separates words.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+// Information about an indexed node file, used to detect changes.
+#[derive(Clone, Copy, PartialEq)]
+struct Document {
+    mtime: u64, // nanoseconds since unix epoch
+    size: u64,
+    words: u64, // total number of words
+}
+
+impl Document {
+    fn for_node(node: &Node) -> io::Result<Document> {
+        let (mtime, size) = file_stamp(node.node_path())?;
+        Ok(Document { mtime, size, words: 0 })
+    }
+}
+
+// Returns modification time (in nanoseconds since the unix epoch)
+// and size of the given file, used to detect changes.
+fn file_stamp<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    Ok((mtime, metadata.len()))
+}
+
+/// Persistent inverted index over the contents of the nodes in
+/// a storage. Stored in the 'index/content' file of the storage.
+/// Maps every word (see tokenize) to the nodes containing it.
+pub struct ContentIndex {
+    path: PathBuf,
+    docs: HashMap<u64, Document>,
+    words: HashMap<String, HashMap<u64, u64>>, // word -> node -> count
+    dirty: bool,
+    // nodes having a word containing the key, see may_contain.
+    // Only filled while querying, not stored
+    parts: RefCell<HashMap<String, HashSet<u64>>>,
+}
+
+impl ContentIndex {
+    /// Returns the path of the content index file of the given storage.
+    pub fn path_for(storage: &Storage) -> PathBuf {
+        let mut path = storage.path().clone();
+        path.push("index");
+        path.push("content");
+        path
+    }
+
+    /// Loads the content index of the given storage.
+    /// Returns an empty index if there is none yet.
+    /// Does not check whether the index is up-to-date, see refresh.
+    pub fn load(storage: &Storage) -> io::Result<ContentIndex> {
+        let path = ContentIndex::path_for(storage);
+        let mut index = ContentIndex {
+            path: path.clone(),
+            docs: HashMap::new(),
+            words: HashMap::new(),
+            dirty: false,
+            parts: RefCell::new(HashMap::new()),
+        };
+
+        let f = match File::open(&path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(index);
+            }, Err(e) => return Err(e),
+        };
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData,
+            "Invalid content index file");
+        let mut lines = BufReader::new(f).lines();
+        match lines.next() {
+            Some(Ok(ref header)) if header == INDEX_HEADER => (),
+            _ => return Err(invalid()),
+        }
+
+        for line in lines {
+            let line = line?;
+            let mut parts = line.split(' ');
+            match parts.next() {
+                Some("n") => {
+                    let mut nums = parts.map(|p| p.parse::<u64>());
+                    let mut next = || nums.next()
+                        .and_then(|n| n.ok())
+                        .ok_or_else(invalid);
+                    let id = next()?;
+                    let doc = Document {
+                        mtime: next()?,
+                        size: next()?,
+                        words: next()?,
+                    };
+                    index.docs.insert(id, doc);
+                }, Some("w") => {
+                    let word = parts.next().ok_or_else(invalid)?;
+                    let mut nodes = HashMap::new();
+                    for part in parts {
+                        let mut entry = part.splitn(2, ':')
+                            .map(|p| p.parse::<u64>().ok());
+                        match (entry.next(), entry.next()) {
+                            (Some(Some(id)), Some(Some(count))) =>
+                                nodes.insert(id, count),
+                            _ => return Err(invalid()),
+                        };
+                    }
+                    index.words.insert(word.to_string(), nodes);
+                }, _ => return Err(invalid()),
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Writes the index back to disk, if it was changed.
+    /// The file is replaced atomically, so concurrent readers never
+    /// see a partially written index.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut f = Vec::new();
+        writeln!(f, "{}", INDEX_HEADER)?;
+        for (id, doc) in &self.docs {
+            writeln!(f, "n {} {} {} {}", id, doc.mtime, doc.size, doc.words)?;
+        }
+
+        for (word, nodes) in &self.words {
+            write!(f, "w {}", word)?;
+            for (id, count) in nodes {
+                write!(f, " {}:{}", id, count)?;
+            }
+            writeln!(f)?;
+        }
+
+        file::write_atomic(&self.path, &f)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Makes sure the index contains the current content of the given
+    /// node. Does nothing if the node file did not change since
+    /// it was indexed.
+    pub fn update(&mut self, node: &Node) -> io::Result<()> {
+        let mut doc = Document::for_node(node)?;
+        if let Some(old) = self.docs.get(&node.id()) {
+            if old.mtime == doc.mtime && old.size == doc.size {
+                return Ok(());
+            }
+        }
+
+        let content = node.read_content_bytes()?;
+        let words = tokenize(&String::from_utf8_lossy(&content));
+
+        self.remove(node.id());
+        doc.words = words.len() as u64;
+        for word in words {
+            *self.words.entry(word).or_insert_with(HashMap::new)
+                .entry(node.id()).or_insert(0) += 1;
+        }
+
+        self.docs.insert(node.id(), doc);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Removes the node with the given id from the index.
+    pub fn remove(&mut self, id: u64) {
+        if self.docs.remove(&id).is_none() {
+            return;
+        }
+
+        for nodes in self.words.values_mut() {
+            nodes.remove(&id);
+        }
+
+        self.words.retain(|_, nodes| !nodes.is_empty());
+        self.dirty = true;
+    }
+
+    /// Brings the index in sync with the given storage.
+    /// Indexes new and changed nodes (detected via modification time
+    /// and size of the node files) and removes deleted ones.
+    pub fn refresh(&mut self, storage: &Storage) -> io::Result<()> {
+        let mut ids = HashSet::new();
+        // entries that aren't valid nodes are not indexed
+        for node in storage.all_nodes().filter_map(Result::ok) {
+            self.update(&node)?;
+            ids.insert(node.id());
+        }
+
+        let removed = self.docs.keys()
+            .filter(|id| !ids.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in removed {
+            self.remove(id);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the index holds the current content of the node.
+    pub fn is_current(&self, node: &Node) -> bool {
+        match (self.docs.get(&node.id()), Document::for_node(node)) {
+            (Some(old), Ok(now)) => old.mtime == now.mtime &&
+                old.size == now.size,
+            _ => false,
+        }
+    }
+
+    /// Returns whether the node with the given id might contain the
+    /// given string, ignoring case. Words in the middle of the string
+    /// must be words of the node, while those at its start and end
+    /// may also be parts of longer words. Can therefore only be used
+    /// to rule nodes out, the content has to be checked if this
+    /// returns true. Returns None if the node is not indexed.
+    pub fn may_contain(&self, id: u64, s: &str) -> Option<bool> {
+        if !self.docs.contains_key(&id) {
+            return None;
+        }
+
+        let words = tokenize(s);
+        let first_part = s.chars().next().is_some_and(|c| c.is_alphanumeric());
+        let last_part = s.chars().last().is_some_and(|c| c.is_alphanumeric());
+        for (i, word) in words.iter().enumerate() {
+            let part = (i == 0 && first_part) ||
+                (i + 1 == words.len() && last_part);
+            let found = if part {
+                self.part_of_word(id, word)
+            } else {
+                self.words.get(word)
+                    .is_some_and(|nodes| nodes.contains_key(&id))
+            };
+
+            if !found {
+                return Some(false);
+            }
+        }
+
+        Some(true)
+    }
+
+    // Returns whether the node with the given id has a word containing
+    // the given one. The matching nodes are computed once per word.
+    fn part_of_word(&self, id: u64, part: &str) -> bool {
+        let mut parts = self.parts.borrow_mut();
+        parts.entry(part.to_string()).or_insert_with(|| self.words.iter()
+                .filter(|&(word, _)| word.contains(part))
+                .flat_map(|(_, nodes)| nodes.keys().cloned())
+                .collect())
+            .contains(&id)
+    }
+
+    /// Returns the relevance of the node with the given id for the
+    /// given words (tf-idf). Nodes that don't contain any of the words
+    /// have a score of 0.
+    pub fn score(&self, id: u64, words: &[String]) -> f64 {
+        let doc = match self.docs.get(&id) {
+            Some(a) => a,
+            None => return 0.0,
+        };
+
+        let total = self.docs.len() as f64;
+        words.iter().filter_map(|w| self.words.get(w)).map(|nodes| {
+            let count = *nodes.get(&id).unwrap_or(&0) as f64;
+            let tf = count / (doc.words.max(1) as f64);
+            let idf = (total / nodes.len() as f64).ln() + 1.0;
+            tf * idf
+        }).sum()
+    }
+
+    /// Runs the given function on the content index of the given storage
+    /// and saves it afterwards. Does nothing if the storage has no index,
+    /// it will be created on the first content query.
+    pub fn update_existing<F>(storage: &Storage, f: F) -> io::Result<()>
+            where F: FnOnce(&mut ContentIndex) -> io::Result<()> {
+        if !ContentIndex::path_for(storage).is_file() {
+            return Ok(());
+        }
+
+        let mut index = ContentIndex::load(storage)?;
+        f(&mut index)?;
+        index.save()
+    }
+}
+
+// Compact encoding of toml values for the meta cache, a lot faster
+// to read than toml. Every value starts with a type character:
+//  s<len>:<bytes> string, d<len>:<bytes> datetime, i<num>; integer,
+//  f<num>; float, b0/b1 boolean, a<n>; array with n values following,
+//  t<n>; table with n (<len>:<key>, value) pairs following
+fn encode_value(v: &toml::Value, out: &mut String) {
+    fn encode_str(s: &str, out: &mut String) {
+        out.push_str(&s.len().to_string());
+        out.push(':');
+        out.push_str(s);
+    }
+
+    match *v {
+        toml::Value::String(ref s) => {
+            out.push('s');
+            encode_str(s, out);
+        }, toml::Value::Datetime(ref d) => {
+            out.push('d');
+            encode_str(&d.to_string(), out);
+        }, toml::Value::Integer(i) => {
+            out.push_str(&format!("i{};", i));
+        }, toml::Value::Float(f) => {
+            out.push_str(&format!("f{:?};", f));
+        }, toml::Value::Boolean(b) => {
+            out.push_str(if b { "b1" } else { "b0" });
+        }, toml::Value::Array(ref a) => {
+            out.push_str(&format!("a{};", a.len()));
+            for v in a {
+                encode_value(v, out);
+            }
+        }, toml::Value::Table(ref t) => {
+            out.push_str(&format!("t{};", t.len()));
+            for (k, v) in t {
+                encode_str(k, out);
+                encode_value(v, out);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a str,
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    // Reads until (excluding) the given delimiter and skips it.
+    fn until(&mut self, delim: char) -> Option<&'a str> {
+        let len = self.data[self.pos..].find(delim)?;
+        let ret = &self.data[self.pos..self.pos + len];
+        self.pos += len + 1;
+        Some(ret)
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a str> {
+        let ret = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(ret)
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        let len = self.until(':')?.parse::<usize>().ok()?;
+        self.take(len)
+    }
+
+    fn value(&mut self) -> Option<toml::Value> {
+        Some(match self.take(1)? {
+            "s" => toml::Value::String(self.string()?.to_string()),
+            "d" => toml::Value::Datetime(self.string()?.parse().ok()?),
+            "i" => toml::Value::Integer(self.until(';')?.parse().ok()?),
+            "f" => toml::Value::Float(self.until(';')?.parse().ok()?),
+            "b" => toml::Value::Boolean(self.take(1)? == "1"),
+            "a" => {
+                let len = self.until(';')?.parse::<usize>().ok()?;
+                let mut array = Vec::with_capacity(len);
+                for _ in 0..len {
+                    array.push(self.value()?);
+                }
+                toml::Value::Array(array)
+            }, "t" => {
+                let len = self.until(';')?.parse::<usize>().ok()?;
+                let mut table = toml::value::Table::new();
+                for _ in 0..len {
+                    let key = self.string()?.to_string();
+                    table.insert(key, self.value()?);
+                }
+                toml::Value::Table(table)
+            }, _ => return None,
+        })
+    }
+
+    // Reads a meta cache entry: '<id> <mtime> <size> <value>\n'
+    fn entry(&mut self) -> Option<(u64, MetaEntry)> {
+        let id = self.until(' ')?.parse().ok()?;
+        let mtime = self.until(' ')?.parse().ok()?;
+        let size = self.until(' ')?.parse().ok()?;
+        let meta = self.value()?;
+        match self.take(1)? {
+            "\n" => Some((id, MetaEntry { mtime, size, meta })),
+            _ => None,
+        }
+    }
+}
+
+struct MetaEntry {
+    mtime: u64,
+    size: u64,
+    meta: toml::Value,
+}
+
+/// Cache of the parsed meta files of a storage, stored in its
+/// 'index/meta' file. Allows to get the meta values of all nodes
+/// without opening and parsing every single meta file.
+/// Entries are invalidated by modification time and size of
+/// the meta files.
+pub struct MetaCache {
+    path: PathBuf,
+    entries: HashMap<u64, MetaEntry>,
+    dirty: bool,
+}
+
+impl MetaCache {
+    /// Returns the path of the meta cache file of the given storage.
+    pub fn path_for(storage: &Storage) -> PathBuf {
+        let mut path = storage.path().clone();
+        path.push("index");
+        path.push("meta");
+        path
+    }
+
+    /// Loads the meta cache of the given storage.
+    /// Returns an empty cache if there is none yet or it is invalid
+    /// (e.g. written by another version), it will simply be rebuilt.
+    pub fn load(storage: &Storage) -> io::Result<MetaCache> {
+        let path = MetaCache::path_for(storage);
+        let mut cache = MetaCache {
+            path: path.clone(),
+            entries: HashMap::new(),
+            dirty: false,
+        };
+
+        let mut data = String::new();
+        let res = File::open(&path).and_then(|mut f| f.read_to_string(&mut data));
+        match res {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(cache),
+            Err(e) => return Err(e),
+        }
+
+        // entries are separated by newlines but since values may
+        // contain newlines as well, we can't simply split into lines
+        let mut decoder = Decoder { data: &data, pos: 0 };
+        if decoder.until('\n') != Some(META_CACHE_HEADER) {
+            return Ok(cache);
+        }
+
+        while decoder.pos < data.len() {
+            let entry = decoder.entry();
+            match entry {
+                Some((id, entry)) => cache.entries.insert(id, entry),
+                None => {
+                    // invalid cache, just rebuild it
+                    cache.entries.clear();
+                    return Ok(cache);
+                }
+            };
+        }
+
+        Ok(cache)
+    }
+
+    /// Writes the cache back to disk, if it was changed.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut data = String::new();
+        data.push_str(META_CACHE_HEADER);
+        data.push('\n');
+        for (id, entry) in &self.entries {
+            data.push_str(&format!("{} {} {} ", id, entry.mtime, entry.size));
+            encode_value(&entry.meta, &mut data);
+            data.push('\n');
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        file::write_atomic(&self.path, data.as_bytes())?;
+        self.dirty = false;
+        Ok(())
+    }
+    /// Returns the meta value of the given node.
+    /// Only reads the meta file if it changed since it was cached.
+    pub fn get(&mut self, node: &Node) -> Result<&toml::Value, toml::LoadError> {
+        let (mtime, size) = file_stamp(node.meta_path())
+            .map_err(toml::LoadError::Open)?;
+        let current = self.entries.get(&node.id())
+            .is_some_and(|e| e.mtime == mtime && e.size == size);
+        if !current {
+            let meta = node.load_meta()?;
+            self.entries.insert(node.id(), MetaEntry { mtime, size, meta });
+            self.dirty = true;
+        }
+
+        Ok(&self.entries[&node.id()].meta)
+    }
+
+    /// Removes the entries of nodes whose meta file doesn't exist
+    /// anymore. The nodes with the given ids are known to exist
+    /// and not checked.
+    pub fn prune(&mut self, storage: &Storage, existing: &HashSet<u64>) {
+        let len = self.entries.len();
+        let mut meta = storage.path().clone();
+        meta.push("meta");
+        self.entries.retain(|id, _| {
+            meta.push(id.to_string());
+            let keep = existing.contains(id) || meta.is_file();
+            meta.pop();
+            keep
+        });
+        self.dirty |= len != self.entries.len();
+    }
+}
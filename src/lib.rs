@@ -1,15 +1,23 @@
 #[macro_use] extern crate serde_derive;
+extern crate serde;
 #[macro_use] extern crate nom;
+extern crate chrono;
+extern crate fs2;
+#[macro_use] extern crate log;
 
 pub mod config;
 pub mod storage;
 pub mod node;
+pub mod error;
 
 pub use config::*;
 pub use storage::*;
 pub use node::*;
+pub use error::Error;
 
 pub mod toml;
 pub mod pattern;
+pub mod index;
 
 mod tree;
+mod file;
@@ -1,20 +1,47 @@
 use super::storage::Storage;
 use super::toml;
+use super::toml::ValueImpl;
+use super::file;
+use super::error::Error;
 
 use std::path::PathBuf;
 use std::fs;
+use std::fs::File;
 use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::cell::OnceCell;
+use serde::de::DeserializeOwned;
+use chrono::{SecondsFormat, Utc};
 
 pub struct Node<'a, 'b: 'a> {
     storage: &'a Storage<'b>,
     id: u64,
-    archived: bool
+    archived: bool,
+    meta: OnceCell<toml::Value>, // cached meta, see meta()
 }
 
 impl<'a, 'b> Node<'a, 'b> {
-    // use default argument instead?
+    /// Returns the node with the given id.
+    /// Whether it is archived is read from its meta file (which is
+    /// cached, see meta). For nodes that don't have this information
+    /// yet, both locations of the node file are probed.
     pub fn new(storage: &'a Storage<'b>, id: u64) -> Node<'a, 'b> {
-        let mut node = Node{storage, id, archived: false};
+        let mut node = Node{storage, id, archived: false,
+            meta: OnceCell::new()};
+        if let Ok(meta) = node.load_meta() {
+            let recorded = meta.get("archived").and_then(|v| v.as_bool());
+            let _ = node.meta.set(meta);
+            if let Some(archived) = recorded {
+                node.archived = archived;
+                if node.node_path().exists() {
+                    return node;
+                }
+
+                node.archived = false;
+            }
+        }
+
         if !node.exists() {
             node.archived = true;
             node.archived = node.exists();
@@ -25,7 +52,7 @@ impl<'a, 'b> Node<'a, 'b> {
 
     pub fn new_archived(storage: &'a Storage<'b>, id: u64, archived: bool)
             -> Node<'a, 'b> {
-        Node{storage, id, archived}
+        Node{storage, id, archived, meta: OnceCell::new()}
     }
 
     /// Returns the nodes id.
@@ -34,16 +61,113 @@ impl<'a, 'b> Node<'a, 'b> {
     }
 
     /// Returns the parsed meta toml value.
+    /// Always reads the meta file, see meta for a cached version.
     pub fn load_meta(&self) -> Result<toml::Value, toml::LoadError> {
-        <toml::Value as toml::ValueImpl>::load(self.meta_path())
+        toml::Value::load(self.meta_path())
+    }
+
+    /// Returns the meta toml value.
+    /// The meta file is only read the first time, later calls return
+    /// the cached value.
+    pub fn meta(&self) -> Result<&toml::Value, toml::LoadError> {
+        if let Some(meta) = self.meta.get() {
+            return Ok(meta);
+        }
+
+        let meta = self.load_meta()?;
+        Ok(self.meta.get_or_init(|| meta))
+    }
+
+    /// Returns the cached meta toml value for modification, see meta.
+    /// Changes are only written to the meta file by save_meta.
+    pub fn meta_mut(&mut self) -> Result<&mut toml::Value, toml::LoadError> {
+        if self.meta.get().is_none() {
+            let meta = self.load_meta()?;
+            let _ = self.meta.set(meta);
+        }
+
+        Ok(self.meta.get_mut().expect("Internal error, meta not cached"))
+    }
+
+    /// Writes the cached meta value (see meta_mut) to the meta file.
+    /// Does nothing if the meta value was never loaded.
+    /// The file is formatted again, i.e. comments are lost, see
+    /// patch_meta for only changing single entries.
+    pub fn save_meta(&self) -> Result<(), Error> {
+        match self.meta.get() {
+            Some(meta) => meta.save(self.meta_path())
+                .map_err(|e| self.file_error(self.meta_path(), e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets (or removes, for None) the given top-level entries in the
+    /// meta file, which is read again for this. Only the lines of these
+    /// entries are changed, unless they can't be changed in place,
+    /// see toml::patch. Other than save_meta, this keeps the comments
+    /// and order of entries in the meta file.
+    pub fn patch_meta(&mut self, entries: &[(&str, Option<toml::Value>)])
+            -> Result<(), Error> {
+        let path = self.meta_path();
+        let doc = fs::read_to_string(&path)
+            .map_err(|e| self.file_error(path.clone(), e))?;
+        let (doc, meta) = toml::patch(&doc, entries)
+            .map_err(|e| Error::Meta {
+                id: self.id,
+                path: path.clone(),
+                cause: toml::LoadError::Parse(e),
+            })?;
+        file::write_atomic(&path, doc.as_bytes())
+            .map_err(|e| self.file_error(path, e))?;
+        self.meta = OnceCell::new();
+        let _ = self.meta.set(meta);
+        Ok(())
+    }
+
+    /// Deserializes the meta value into the given type.
+    pub fn meta_as<T: DeserializeOwned>(&self) -> Result<T, toml::LoadError> {
+        self.meta()?.clone().try_into().map_err(toml::LoadError::Parse)
+    }
+
+    /// Reads the content of the node.
+    /// Fails if it isn't valid utf-8, see read_content_bytes.
+    pub fn read_content(&self) -> io::Result<String> {
+        let mut content = String::new();
+        File::open(self.node_path())?.read_to_string(&mut content)?;
+        Ok(content)
+    }
+
+    /// Reads the raw content of the node.
+    pub fn read_content_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        File::open(self.node_path())?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Returns a buffered reader for the content of the node.
+    /// Useful for large nodes or to only read the first lines.
+    pub fn content_reader(&self) -> io::Result<BufReader<File>> {
+        Ok(BufReader::new(File::open(self.node_path())?))
+    }
+
+    /// Replaces the content of the node. The content file is written
+    /// atomically, it is created if it does not exist.
+    pub fn write_content(&self, content: &[u8]) -> Result<(), Error> {
+        file::write_atomic(self.node_path(), content)
+            .map_err(|e| self.file_error(self.node_path(), e))
     }
 
     /// Returns the path of node file.
     /// Does not guarantee it exists.
     pub fn node_path(&self) -> PathBuf {
+        self.path_archived(self.archived)
+    }
+
+    // Returns the path of the node file if the node is (not) archived.
+    fn path_archived(&self, archived: bool) -> PathBuf {
         let mut pb = self.storage.path().clone();
         pb.push("nodes");
-        if self.archived {
+        if archived {
             pb.push("archive");
         }
         pb.push(&self.id.to_string());
@@ -60,45 +184,106 @@ impl<'a, 'b> Node<'a, 'b> {
     }
 
     /// Returns whether the node exists.
+    /// Nodes that only have a node or a meta file don't exist, a warning
+    /// is logged for them.
     pub fn exists(&self) -> bool {
-        if self.node_path().exists() {
-            if self.meta_path().exists() {
-                true
-            } else {
-                println!("Node {} has no meta file", self.id);
+        let (node, meta) = (self.node_path(), self.meta_path());
+        match (node.exists(), meta.exists()) {
+            (true, true) => true,
+            (false, false) => false,
+            (true, false) => {
+                warn!("{}", Error::Incomplete { id: self.id, path: meta });
+                false
+            }, (false, true) => {
+                // archived nodes are probed in the other location first
+                debug!("{}", Error::Incomplete { id: self.id, path: node });
                 false
             }
-        } else {
-            if self.node_path().exists() {
-                println!("Meta file for non-existent node {}", self.id);
-            }
-            false
         }
     }
 
+    /// Returns a reference to this node that is qualified with the name
+    /// of its storage, like '42@n:public'.
+    pub fn reference(&self) -> String {
+        format!("{}@n:{}", self.id, self.storage.name())
+    }
+
     /// Returns the associates storage
     pub fn storage(&self) -> &Storage<'b> {
         self.storage
     }
 
     /// Removes this node.
-    pub fn remove(&self) -> io::Result<()> {
-        fs::remove_file(self.node_path())?;
-        fs::remove_file(self.meta_path())
+    pub fn remove(&self) -> Result<(), Error> {
+        let _lock = self.storage.lock()?;
+        for path in [self.node_path(), self.meta_path()] {
+            fs::remove_file(&path).map_err(|e| self.file_error(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Archives or unarchives the node, see archive_with_reason.
+    pub fn archive(&mut self, a: bool) -> Result<(), Error> {
+        self.archive_with_reason(a, None)
     }
 
-    pub fn archive(&mut self, a: bool) -> io::Result<()> {
-        if a == self.archived {
+    /// Archives or unarchives the node. Moves the node file and records
+    /// the state as 'archived' in the meta file. Archiving also sets
+    /// 'archive_date' and (if given) 'archive_reason', unarchiving
+    /// removes them. The rest of the meta file is kept as it is, see
+    /// patch_meta.
+    pub fn archive_with_reason(&mut self, a: bool, reason: Option<&str>)
+            -> Result<(), Error> {
+        let _lock = self.storage.lock()?;
+
+        // read the meta file again, the cached value might be outdated
+        let meta = self.load_meta().map_err(|cause| Error::Meta {
+            id: self.id,
+            path: self.meta_path(),
+            cause,
+        })?;
+        let recorded = meta.get("archived").and_then(|v| v.as_bool());
+        if a == self.archived && recorded == Some(a) && reason.is_none() {
             return Ok(());
         }
 
         let prev = self.node_path();
+        let moved = a != self.archived;
+        if moved {
+            fs::rename(&prev, self.path_archived(a))
+                .map_err(|e| self.file_error(prev.clone(), e))?;
+        }
+
+        let mut entries = vec!(("archived", Some(toml::Value::from(a))));
+        if a {
+            if recorded != Some(true) {
+                let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+                entries.push(("archive_date", Some(toml::Value::from(now))));
+            }
+
+            if let Some(reason) = reason {
+                let reason = toml::Value::from(reason);
+                entries.push(("archive_reason", Some(reason)));
+            }
+        } else {
+            entries.push(("archive_date", None));
+            entries.push(("archive_reason", None));
+        }
+
+        if let Err(e) = self.patch_meta(&entries) {
+            if moved {
+                let _ = fs::rename(self.path_archived(a), prev);
+            }
+
+            return Err(e);
+        }
+
         self.archived = a;
-        let now = self.node_path();
-         fs::rename(prev, now)
+        Ok(())
     }
 
-    pub fn toggle_archive(&mut self) -> io::Result<()> {
+    pub fn toggle_archive(&mut self) -> Result<(), Error> {
         let a = !self.archived;
         self.archive(a)
     }
@@ -106,5 +291,9 @@ impl<'a, 'b> Node<'a, 'b> {
     pub fn archived(&self) -> bool {
         self.archived
     }
-}
 
+    // Returns an error for accessing the given file of this node.
+    fn file_error(&self, path: PathBuf, cause: io::Error) -> Error {
+        Error::NodeFile { id: self.id, path, cause }
+    }
+}
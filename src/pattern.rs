@@ -3,15 +3,24 @@ extern crate regex;
 use super::toml;
 use super::node;
 use super::tree;
+use super::index;
 use super::toml::ValueImpl;
+use super::error::Error;
 
+use std::fmt;
+use std::io;
+use std::error;
 use std::str;
 use std::str::FromStr;
+use std::cmp::Ordering;
 use std::string::ToString;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
 use self::regex::Regex;
-use nom::IResult;
+use nom::{ErrorKind, IResult};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate,
+    NaiveDateTime, TimeZone};
 
 pub enum MatchString {
     Match(Regex),
@@ -21,10 +30,18 @@ pub enum MatchString {
 pub enum CondType {
     Exists,
     Type(String),
-    Equals(String),
+    /// Comma-separated list of values, 'tags=a,b'
+    Equals(Vec<String>),
+    /// Like Equals but arrays are compared order-insensitive, 'tags*=a,b'.
+    /// Also used for '=' if Definitions::unordered_arrays is set.
+    EqualsUnordered(Vec<String>),
     Matches(Vec<MatchString>),
     Smaller(String),
-    Greater(String)
+    Greater(String),
+    /// Case-insensitive containment, 'title~foo'
+    Insensitive(String),
+    /// Typo-tolerant containment, 'title%foo'
+    Fuzzy(String),
 }
 
 pub struct Cond {
@@ -39,31 +56,277 @@ pub enum CondNodeType {
     Cond(Cond),
 }
 
-type CondNode = tree::Node<CondNodeType>;
+pub type CondNode = tree::Node<CondNodeType>;
 
+/// A node as seen by conditions.
+/// Meta and content of the node are only loaded when a condition
+/// needs them and then cached.
 pub struct MetaNode<'a, 'b: 'a, 'c: 'a, 'd> {
     pub node: &'a node::Node<'b, 'c>,
-    pub meta: &'d toml::Value
+    /// Content index of the storage, used for content queries if given
+    pub index: Option<&'d index::ContentIndex>,
+    /// Meta cache of the storage, used to load meta if given
+    pub cache: Option<&'d RefCell<index::MetaCache>>,
+    meta: OnceCell<Option<toml::Value>>,
+    content: OnceCell<Option<String>>,
 }
 
-pub fn equals(a: &toml::Value, b: &str) -> bool {
-    match a {
-        &toml::Value::String(ref s) => s == b,
-        &toml::Value::Integer(ref i) => {
-            if let Ok(b) = i64::from_str(b) { *i == b } else { false }
-        } &toml::Value::Float(ref f) => {
-            if let Ok(b) = f64::from_str(b) { *f == b } else { false }
-        } &toml::Value::Array(ref a) => {
-            let mut b = b.split(',');
-            for val in a {
-                let n = b.next();
-                if n.is_none() || !equals(&val, n.unwrap()) {
-                    return false;
+impl<'a, 'b, 'c, 'd> MetaNode<'a, 'b, 'c, 'd> {
+    pub fn new(node: &'a node::Node<'b, 'c>) -> MetaNode<'a, 'b, 'c, 'd> {
+        MetaNode {
+            node,
+            index: None,
+            cache: None,
+            meta: OnceCell::new(),
+            content: OnceCell::new(),
+        }
+    }
+
+    /// Creates a MetaNode with already loaded meta.
+    pub fn with_meta(node: &'a node::Node<'b, 'c>, meta: toml::Value)
+            -> MetaNode<'a, 'b, 'c, 'd> {
+        let ret = MetaNode::new(node);
+        let _ = ret.meta.set(Some(meta));
+        ret
+    }
+
+    /// Returns the meta value of the node, loads it if needed.
+    /// Returns None if it could not be loaded.
+    pub fn meta(&self) -> Option<&toml::Value> {
+        self.meta.get_or_init(|| {
+            let meta = match self.cache {
+                Some(cache) => cache.borrow_mut().get(self.node).cloned(),
+                None => self.node.load_meta(),
+            };
+
+            match meta {
+                Ok(a) => Some(a),
+                Err(e) => {
+                    warn!("{}", Error::Meta {
+                        id: self.node.id(),
+                        path: self.node.meta_path(),
+                        cause: e,
+                    });
+                    None
                 }
             }
+        }).as_ref()
+    }
 
-            true
-        }, _ => false
+    /// Returns the content of the node, reads it if needed.
+    /// Returns None if it could not be read.
+    pub fn content(&self) -> Option<&str> {
+        self.content.get_or_init(|| read_content(self.node))
+            .as_ref().map(|s| s.as_str())
+    }
+}
+
+/// Returns whether the meta value a equals the pattern values b.
+/// The type of the comparison is deduced from the meta value:
+///  - booleans equal 'true' or 'false'
+///  - numbers are compared numerically
+///  - datetimes (and strings holding a datetime, like the 'created'
+///    field) chronologically, where a date without time (or 'today',
+///    'yesterday' and 'tomorrow') matches the whole day
+///  - arrays equal the list of values, each element compared
+///    according to its type. If unordered is set, the order of the
+///    elements does not matter
+///  - tables equal an inline table, like '{ a = 1, b = "x" }'
+///
+/// Everything but arrays is compared with the values joined by ','.
+pub fn equals(a: &toml::Value, b: &[String], unordered: bool) -> bool {
+    let array = match *a {
+        toml::Value::Array(ref array) => array,
+        _ => return value_equals(a, &b.join(","), unordered),
+    };
+
+    // a single empty value matches the empty array
+    let b = match b {
+        [v] if v.trim().is_empty() => &[],
+        _ => b,
+    };
+
+    if b.len() != array.len() {
+        return false;
+    }
+
+    if !unordered {
+        return array.iter().zip(b)
+            .all(|(a, b)| value_equals(a, b, unordered));
+    }
+
+    // every element must be matched by a different value
+    let mut used = vec!(false; b.len());
+    array.iter().all(|a| {
+        let pos = b.iter().enumerate().position(|(i, b)|
+            !used[i] && value_equals(a, b, unordered));
+        pos.map(|i| used[i] = true).is_some()
+    })
+}
+
+// Returns whether the meta value a equals the single pattern value b,
+// see equals.
+fn value_equals(a: &toml::Value, b: &str, unordered: bool) -> bool {
+    match *a {
+        toml::Value::String(ref s) => s == b ||
+            (parse_datetime(s).is_some() && datetime_equals(s, b)),
+        toml::Value::Integer(i) => {
+            if let Ok(b) = i64::from_str(b) {
+                i == b
+            } else {
+                f64::from_str(b).map(|b| i as f64 == b).unwrap_or(false)
+            }
+        }, toml::Value::Float(f) => {
+            f64::from_str(b).map(|b| f == b).unwrap_or(false)
+        }, toml::Value::Boolean(v) => {
+            bool::from_str(b).map(|b| v == b).unwrap_or(false)
+        }, toml::Value::Datetime(ref d) => {
+            datetime_equals(&d.to_string(), b)
+        }, toml::Value::Array(_) => {
+            equals(a, &[b.to_string()], unordered)
+        }, toml::Value::Table(_) => {
+            format!("v = {}", b).parse::<toml::Value>().ok()
+                .and_then(|v| v.get("v").cloned())
+                .is_some_and(|v| v == *a)
+        },
+    }
+}
+
+// Returns whether the datetime a equals the pattern value b,
+// see equals.
+fn datetime_equals(a: &str, b: &str) -> bool {
+    let (a, date) = match (parse_datetime(a), parse_date_value(b)) {
+        (Some(a), Some(date)) => (a, date),
+        _ => return false,
+    };
+
+    let whole_day = NaiveDate::parse_from_str(b, "%Y-%m-%d").is_ok() ||
+        ["today", "yesterday", "tomorrow"].contains(&b);
+    if whole_day {
+        a.with_timezone(&Local).date_naive() ==
+            date.with_timezone(&Local).date_naive()
+    } else {
+        a == date
+    }
+}
+
+/// Parses a datetime as given in a pattern or stored in meta.
+/// Accepts full rfc3339 datetimes, datetimes without offset and
+/// plain dates (like 2018-03-01). The latter two are interpreted in
+/// local time, plain dates as the start of the day.
+pub fn parse_datetime(s: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+        return Some(d);
+    }
+
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+            .and_then(|d| d.and_hms_opt(0, 0, 0)));
+    naive.and_then(|n| Local.from_local_datetime(&n).earliest())
+        .map(|d| d.with_timezone(d.offset()))
+}
+
+/// Parses a datetime value given in a pattern.
+/// Additionally to everything parse_datetime accepts, this allows
+/// the keywords 'now', 'today', 'yesterday' and 'tomorrow' (the latter
+/// three meaning the start of that day) as well as offsets relative
+/// to the current time in hours, days or weeks, like -12h, -7d or +2w.
+pub fn parse_date_value(s: &str) -> Option<DateTime<FixedOffset>> {
+    let now = Local::now();
+    let now = now.with_timezone(now.offset());
+    let day_start = |days: i64| {
+        let day = now.date_naive() + Duration::days(days);
+        day.and_hms_opt(0, 0, 0)
+            .and_then(|d| Local.from_local_datetime(&d).earliest())
+            .map(|d| d.with_timezone(d.offset()))
+    };
+
+    match s {
+        "now" => Some(now),
+        "today" => day_start(0),
+        "yesterday" => day_start(-1),
+        "tomorrow" => day_start(1),
+        _ => match parse_relative(s) {
+            Some(offset) => now.checked_add_signed(offset),
+            None => parse_datetime(s),
+        }
+    }
+}
+
+// Parses a relative time offset like -7d or +12h.
+fn parse_relative(s: &str) -> Option<Duration> {
+    let sign = match s.chars().next() {
+        Some('-') => -1,
+        Some('+') => 1,
+        _ => return None,
+    };
+
+    let unit = match s.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => c,
+        _ => return None,
+    };
+
+    let num = sign * i64::from_str(&s[1..s.len() - 1]).ok()?;
+    match unit {
+        'h' => Duration::try_hours(num),
+        'd' => Duration::try_days(num),
+        'w' => Duration::try_weeks(num),
+        _ => None,
+    }
+}
+
+/// Compares the meta value a with the pattern value b.
+/// The type of the comparison is deduced from the meta value: numbers
+/// are compared numerically, datetimes (and strings holding a datetime,
+/// like the 'created' field) chronologically. For the latter, b may
+/// also be a relative date expression, see parse_date_value.
+/// Returns None if the values cannot be compared.
+pub fn compare(a: &toml::Value, b: &str) -> Option<Ordering> {
+    match *a {
+        toml::Value::Integer(i) => {
+            if let Ok(b) = i64::from_str(b) {
+                Some(i.cmp(&b))
+            } else {
+                f64::from_str(b).ok().and_then(|b| (i as f64).partial_cmp(&b))
+            }
+        }, toml::Value::Float(f) => {
+            f64::from_str(b).ok().and_then(|b| f.partial_cmp(&b))
+        }, toml::Value::Datetime(ref d) => {
+            compare_datetimes(&d.to_string(), b)
+        }, toml::Value::String(ref s) => {
+            compare_datetimes(s, b)
+        }, _ => None
+    }
+}
+
+fn compare_datetimes(a: &str, b: &str) -> Option<Ordering> {
+    match (parse_datetime(a), parse_date_value(b)) {
+        (Some(a), Some(b)) => Some(a.cmp(&b)),
+        _ => None,
+    }
+}
+
+/// The type names that can be used in type predicates, like 'due?date'.
+pub const TYPE_NAMES: &[&str] = &["string", "int", "integer", "float",
+    "number", "bool", "boolean", "date", "datetime", "array", "table"];
+
+/// Returns whether the given value is of the given type.
+/// Strings holding a valid datetime (like the 'created' field) are
+/// considered dates as well. For valid type names, see TYPE_NAMES.
+pub fn has_type(v: &toml::Value, t: &str) -> bool {
+    match t {
+        "string" => v.is_str(),
+        "int" | "integer" => v.is_integer(),
+        "float" => v.is_float(),
+        "number" => v.is_integer() || v.is_float(),
+        "bool" | "boolean" => v.is_bool(),
+        "date" | "datetime" => v.is_datetime() ||
+            v.as_str().and_then(parse_datetime).is_some(),
+        "array" => v.is_array(),
+        "table" => v.is_table(),
+        _ => false,
     }
 }
 
@@ -96,53 +359,274 @@ pub fn matches(a: &toml::Value, b: &Vec<MatchString>) -> bool {
             }
 
             true
+        }, &toml::Value::Integer(i) => {
+            b.iter().all(|given| match given {
+                &MatchString::String(ref a) => in_range(i, a),
+                &MatchString::Match(_) => false,
+            })
         }, _ => false
     }
 }
 
+/// Returns whether the given value contains the given string,
+/// ignoring case. Arrays contain it if any of their elements does.
+pub fn contains_insensitive(a: &toml::Value, b: &str) -> bool {
+    let b = b.to_lowercase();
+    match a {
+        &toml::Value::String(ref s) => s.to_lowercase().contains(&b),
+        &toml::Value::Array(ref array) => array.iter()
+            .filter_map(|v| v.as_str())
+            .any(|s| s.to_lowercase().contains(&b)),
+        _ => false,
+    }
+}
+
+/// Returns the number of typos (insertions, deletions or substitutions)
+/// allowed for a fuzzy match of the given pattern length.
+pub fn fuzzy_tolerance(len: usize) -> usize {
+    if len < 3 { 0 } else { (len + 1) / 4 }
+}
+
+/// Returns the fewest typos needed to make the given pattern appear
+/// in the given text, ignoring case.
+pub fn fuzzy_distance(pattern: &str, text: &str) -> usize {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+
+    // after reading text char j, row holds the distances of the pattern
+    // prefixes to the best substring of the text ending at j. Since
+    // matches can start anywhere, the empty prefix always has distance 0.
+    // The row is updated in place, diag holds the previous value of
+    // the entry before the current one.
+    let mut row = (0..pattern.len() + 1).collect::<Vec<_>>();
+    let mut best = pattern.len();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        let mut diag = row[0];
+        for (i, &p) in pattern.iter().enumerate() {
+            let above = row[i + 1];
+            let subst = diag + if p == c { 0 } else { 1 };
+            row[i + 1] = subst.min(above + 1).min(row[i] + 1);
+            diag = above;
+        }
+
+        best = best.min(row[pattern.len()]);
+    }
+
+    best
+}
+
+/// Fuzzy matches the given pattern against the given text.
+/// Returns None if the text does not contain the pattern within the
+/// tolerated number of typos (see fuzzy_tolerance), otherwise a score
+/// between 0 and 1, where 1 means the text contains the pattern exactly.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<f64> {
+    let len = pattern.chars().count();
+    if len == 0 {
+        return Some(1.0);
+    }
+
+    let dist = fuzzy_distance(pattern, text);
+    if dist > fuzzy_tolerance(len) {
+        None
+    } else {
+        Some(1.0 - dist as f64 / len as f64)
+    }
+}
+
+/// Fuzzy matches the given pattern against the given value.
+/// For arrays, the best matching element is used.
+/// See fuzzy_score.
+pub fn fuzzy_matches(a: &toml::Value, pattern: &str) -> Option<f64> {
+    match a {
+        &toml::Value::String(ref s) => fuzzy_score(pattern, s),
+        &toml::Value::Array(ref array) => array.iter()
+            .filter_map(|v| v.as_str())
+            .filter_map(|s| fuzzy_score(pattern, s))
+            .fold(None, |best, s| Some(best.map_or(s, |b: f64| b.max(s)))),
+        _ => None,
+    }
+}
+
+/// Returns whether the given integer lies in the given range.
+/// The range has the form 'a..b' and includes both bounds, either of
+/// which can be omitted. A single number is matched exactly.
+pub fn in_range(i: i64, range: &str) -> bool {
+    let mut bounds = range.splitn(2, "..");
+    let low = bounds.next().unwrap_or("");
+    let high = match bounds.next() {
+        Some(high) => high,
+        None => return i64::from_str(low).map(|v| v == i).unwrap_or(false),
+    };
+
+    let check = |bound: &str, f: &dyn Fn(i64) -> bool| {
+        bound.is_empty() || i64::from_str(bound).map(f).unwrap_or(false)
+    };
+    check(low, &|v| i >= v) && check(high, &|v| i <= v)
+}
+
+/// The pseudo fields that are computed from a node and its files
+/// instead of being read from its meta file. In patterns they are
+/// prefixed with an '@', e.g. '@id:59..102' or '@size>1000'.
+pub const PSEUDO_FIELDS: &[&str] = &["id", "archived", "size", "lines",
+    "mtime", "storage"];
+
+/// Computes the value of the given pseudo field (without the '@')
+/// for the given node. Returns None if the field is unknown or its
+/// value could not be computed.
+pub fn pseudo_field(node: &node::Node, name: &str) -> Option<toml::Value> {
+    let metadata = || match fs::metadata(node.node_path()) {
+        Ok(a) => Some(a),
+        Err(e) => {
+            warn!("{}", file_error(node, e));
+            None
+        }
+    };
+
+    match name {
+        "id" => Some(toml::Value::Integer(node.id() as i64)),
+        "archived" => Some(toml::Value::Boolean(node.archived())),
+        "storage" => Some(toml::Value::from(node.storage().name())),
+        "size" => metadata().map(|m| toml::Value::Integer(m.len() as i64)),
+        "mtime" => {
+            let modified = metadata().and_then(|m| m.modified().ok())?;
+            let modified = DateTime::<Local>::from(modified).to_rfc3339();
+            modified.parse().ok().map(toml::Value::Datetime)
+        }, "lines" => {
+            let content = match node.read_content_bytes() {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("{}", file_error(node, e));
+                    return None;
+                },
+            };
+
+            let mut lines = content.iter().filter(|&&c| c == b'\n').count();
+            if content.last().is_some_and(|&c| c != b'\n') {
+                lines += 1;
+            }
+
+            Some(toml::Value::Integer(lines as i64))
+        }, _ => None,
+    }
+}
+
+fn read_content(node: &node::Node) -> Option<String> {
+    match node.read_content() {
+        Ok(a) => Some(a),
+        Err(e) => {
+            warn!("{}", file_error(node, e));
+            None
+        },
+    }
+}
+
+// Returns an error for accessing the node file of the given node.
+fn file_error(node: &node::Node, cause: io::Error) -> Error {
+    Error::NodeFile { id: node.id(), path: node.node_path(), cause }
+}
+
+/// Returns whether the content of the given node matches the given
+/// string or regex. Strings are searched for as they are. If possible,
+/// the content index is used to rule out nodes without reading them.
+pub fn content_matches(node: &MetaNode, pattern: &MatchString) -> bool {
+    if let (MatchString::String(s), Some(index)) = (pattern, node.index) {
+        if index.is_current(node.node) &&
+                index.may_contain(node.node.id(), s) == Some(false) {
+            return false;
+        }
+    }
+
+    let content = match node.content() {
+        Some(a) => a,
+        None => return false,
+    };
+
+    match *pattern {
+        MatchString::Match(ref regex) => regex.is_match(content),
+        MatchString::String(ref s) => content.contains(s),
+    }
+}
+
+/// Returns the words of all content string queries in the given
+/// condition tree, i.e. the words a matching node can be ranked by.
+pub fn content_words(cond: &CondNode) -> Vec<String> {
+    match cond.data {
+        // terms below a 'not' are not relevant for ranking
+        CondNodeType::Not => Vec::new(),
+        CondNodeType::Cond(ref cond) => match cond.cond_type {
+            CondType::Matches(ref patterns) if cond.entry == "c" => {
+                patterns.iter().flat_map(|p| match *p {
+                    MatchString::String(ref s) => index::tokenize(s),
+                    MatchString::Match(_) => Vec::new(),
+                }).collect()
+            }, _ => Vec::new(),
+        }, _ => cond.children.iter().flat_map(content_words).collect(),
+    }
+}
+
+/// Returns whether the given condition tree queries node content.
+pub fn uses_content(cond: &CondNode) -> bool {
+    match cond.data {
+        CondNodeType::Cond(ref cond) => cond.entry == "c",
+        _ => cond.children.iter().any(uses_content),
+    }
+}
+
+/// Returns whether the given value fulfills the given condition.
+pub fn check_value(v: &toml::Value, cond_type: &CondType) -> bool {
+    match *cond_type {
+        CondType::Exists => true,
+        CondType::Type(ref t) => has_type(v, t),
+        CondType::Equals(ref value) => equals(v, value, false),
+        CondType::EqualsUnordered(ref value) => equals(v, value, true),
+        CondType::Matches(ref value) => matches(v, value),
+        CondType::Greater(ref value) =>
+            compare(v, value) == Some(Ordering::Greater),
+        CondType::Smaller(ref value) =>
+            compare(v, value) == Some(Ordering::Less),
+        CondType::Insensitive(ref value) => contains_insensitive(v, value),
+        CondType::Fuzzy(ref value) => fuzzy_matches(v, value).is_some(),
+    }
+}
+
 pub fn check_cond(node: &MetaNode, cond: &Cond) -> bool {
     // special: content
     if cond.entry == "c" {
         // TODO: check if type is text
-        let mut s = String::new();
-        let res = File::open(node.node.node_path())
-            .and_then(|mut f| f.read_to_string(&mut s));
-        if let Err(e) = res {
-            println!("Failed to read '{}': {}", node.node.id(), e);
-            return false;
-        }
-
         if let &CondType::Matches(ref patterns) = &cond.cond_type {
-            for pattern in patterns {
-                match pattern {
-                    &MatchString::Match(ref a) =>
-                        if !a.is_match(&s) { return false; },
-                    &MatchString::String(ref a) =>
-                        if !s.contains(a) { return false; },
-                }
-            }
-            return true
-        } else if let &CondType::Equals(ref v) = &cond.cond_type {
-            return s == *v;
+            return patterns.iter().all(|p| content_matches(node, p));
+        } else if let &CondType::Equals(ref v) |
+                &CondType::EqualsUnordered(ref v) = &cond.cond_type {
+            return node.content() == Some(v.join(",").as_str());
+        } else if let &CondType::Insensitive(ref v) = &cond.cond_type {
+            return node.content().is_some_and(|c|
+                c.to_lowercase().contains(&v.to_lowercase()));
+        } else if let &CondType::Fuzzy(ref v) = &cond.cond_type {
+            return node.content()
+                .is_some_and(|c| fuzzy_score(v, c).is_some());
         }
     }
 
-    // otherwise: meta
-    let v = node.meta.find(&cond.entry);
-    if v.is_none() {
-        return false;
+    // special: pseudo fields
+    // they always exist, so for boolean ones (like '@archived') a plain
+    // existence check tests the value instead
+    if cond.entry.starts_with('@') {
+        let v = match pseudo_field(node.node, &cond.entry[1..]) {
+            Some(a) => a,
+            None => return false,
+        };
+
+        return match (&cond.cond_type, v.as_bool()) {
+            (&CondType::Exists, Some(b)) => b,
+            _ => check_value(&v, &cond.cond_type),
+        };
     }
 
-    let v = v.unwrap();
-    match &cond.cond_type {
-        &CondType::Exists => true,
-        &CondType::Equals(ref value) => equals(&v, &value),
-        &CondType::Matches(ref value) => matches(&v, &value),
-        _ => {
-            // TODO: implement!
-            println!("not implemented");
-            false
-        }
+    // otherwise: meta
+    // with wildcards (like 'tasks.*.done') any matching value counts
+    match node.meta() {
+        Some(meta) => meta.find_all(&cond.entry).into_iter()
+            .any(|v| check_value(v, &cond.cond_type)),
+        None => false,
     }
 }
 
@@ -173,174 +657,892 @@ pub fn node_matches(node: &MetaNode, cond: &CondNode) -> bool {
     }
 }
 
-pub fn print_cond(cond: &CondNode) {
-    match &cond.data {
-        &CondNodeType::Not => {
-            print!("!(");
-            print_cond(cond.children.first()
-                .expect("Invalid CondNode: 'not' needs a child"));
-            print!(")");
-        }, &CondNodeType::And => {
-            print!("(");
-            let mut first = true;
-            for child in &cond.children {
-                if first {
-                    first = false;
-                } else {
-                    print!(" && ");
-                }
-                print_cond(&child);
+/// Returns how well the given node matches the given condition tree,
+/// for ordering matching nodes by relevance. Fuzzy conditions contribute
+/// their fuzzy score and content queries the score of the content
+/// index (if available). Conditions below a 'not' are ignored.
+/// Only meaningful for nodes that match the condition.
+pub fn score(node: &MetaNode, cond: &CondNode) -> f64 {
+    match cond.data {
+        CondNodeType::Not => 0.0,
+        CondNodeType::Cond(ref cond) => cond_score(node, cond),
+        _ => cond.children.iter().map(|c| score(node, c)).sum(),
+    }
+}
+
+fn cond_score(node: &MetaNode, cond: &Cond) -> f64 {
+    match (cond.entry.as_str(), &cond.cond_type) {
+        ("c", &CondType::Fuzzy(ref v)) => node.content()
+            .and_then(|c| fuzzy_score(v, c)),
+        ("c", &CondType::Matches(ref patterns)) => node.index.map(|index| {
+            let words = patterns.iter().flat_map(|p| match *p {
+                MatchString::String(ref s) => index::tokenize(s),
+                MatchString::Match(_) => Vec::new(),
+            }).collect::<Vec<_>>();
+            index.score(node.node.id(), &words)
+        }),
+        (entry, &CondType::Fuzzy(ref v)) if entry.starts_with('@') =>
+            pseudo_field(node.node, &entry[1..])
+                .and_then(|f| fuzzy_matches(&f, v)),
+        (entry, &CondType::Fuzzy(ref v)) => node.meta()
+            .map(|m| m.find_all(entry))
+            .unwrap_or_default().into_iter()
+            .filter_map(|f| fuzzy_matches(f, v))
+            .fold(None, |best, s| Some(best.map_or(s, |b: f64| b.max(s)))),
+        _ => None,
+    }.unwrap_or(0.0)
+}
+
+/// A field to sort nodes by, see parse_sort.
+pub struct SortKey {
+    /// The meta field or pseudo field (prefixed with '@').
+    pub field: String,
+    pub descending: bool,
+}
+
+/// Returns the value of the given field for the given node, i.e.
+/// a pseudo field (prefixed with '@') or the first matching meta value.
+pub fn field_value(node: &MetaNode, field: &str) -> Option<toml::Value> {
+    match field.strip_prefix('@') {
+        Some(name) => pseudo_field(node.node, name),
+        None => node.meta().and_then(|m| m.find(field)).cloned(),
+    }
+}
+
+// Returns the datetime a value holds, if any.
+fn as_datetime(v: &toml::Value) -> Option<DateTime<FixedOffset>> {
+    match *v {
+        toml::Value::Datetime(ref d) => parse_datetime(&d.to_string()),
+        toml::Value::String(ref s) => parse_datetime(s),
+        _ => None,
+    }
+}
+
+// Orders values of different types.
+fn type_rank(v: &toml::Value) -> u8 {
+    match *v {
+        toml::Value::Boolean(_) => 0,
+        toml::Value::Integer(_) | toml::Value::Float(_) => 1,
+        toml::Value::Datetime(_) => 2,
+        toml::Value::String(_) => 3,
+        toml::Value::Array(_) => 4,
+        toml::Value::Table(_) => 5,
+    }
+}
+
+/// Compares two meta values according to their type, for sorting.
+/// Numbers are compared numerically, datetimes (and strings holding
+/// a datetime, like the 'created' field) chronologically, strings
+/// case-insensitive and arrays element-wise.
+/// Values of different types are ordered by type.
+pub fn compare_values(a: &toml::Value, b: &toml::Value) -> Ordering {
+    use toml::Value::*;
+    if let (Some(a), Some(b)) = (as_datetime(a), as_datetime(b)) {
+        return a.cmp(&b);
+    }
+
+    match (a, b) {
+        (&Integer(a), &Integer(b)) => a.cmp(&b),
+        (&Integer(a), &Float(b)) => (a as f64).total_cmp(&b),
+        (&Float(a), &Integer(b)) => a.total_cmp(&(b as f64)),
+        (&Float(a), &Float(b)) => a.total_cmp(&b),
+        (&Boolean(a), &Boolean(b)) => a.cmp(&b),
+        (String(a), String(b)) => a.to_lowercase()
+            .cmp(&b.to_lowercase())
+            .then_with(|| a.cmp(b)),
+        (Array(a), Array(b)) => a.iter().zip(b)
+            .map(|(a, b)| compare_values(a, b))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+/// Compares the field values of two nodes using the given sort keys,
+/// the values being in the same order as the keys.
+/// Missing values are considered smaller than all others.
+pub fn compare_keys(keys: &[SortKey], a: &[Option<toml::Value>],
+        b: &[Option<toml::Value>]) -> Ordering {
+    for (key, (a, b)) in keys.iter().zip(a.iter().zip(b)) {
+        let order = match (a, b) {
+            (Some(a), Some(b)) => compare_values(a, b),
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => Ordering::Equal,
+        };
+
+        let order = if key.descending { order.reverse() } else { order };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+
+    Ordering::Equal
+}
+
+// Estimated cost of evaluating the given condition. Pseudo fields known
+// without touching the filesystem are the cheapest, followed by the
+// ones that need a stat, meta fields and finally content.
+fn cond_cost(cond: &Cond) -> u32 {
+    match cond.entry.as_str() {
+        "@id" | "@archived" | "@storage" => 0,
+        "@size" | "@mtime" => 2,
+        "@lines" => 4,
+        "c" => match cond.cond_type {
+            CondType::Matches(ref patterns) if patterns.iter()
+                .all(|p| matches!(*p, MatchString::String(_))) => 3,
+            CondType::Equals(_) | CondType::EqualsUnordered(_) |
+                CondType::Insensitive(_) => 3,
+            _ => 4,
+        }, _ => 1,
+    }
+}
+
+// Estimated cost of evaluating the given condition tree.
+fn cost(cond: &CondNode) -> u32 {
+    match cond.data {
+        CondNodeType::Cond(ref cond) => cond_cost(cond),
+        _ => cond.children.iter().map(cost).sum(),
+    }
+}
+
+/// Normalizes the given condition tree and reorders it so that
+/// it can be evaluated as cheap as possible, i.e. conditions that are
+/// cheap to check come first and may short-circuit expensive ones.
+/// Nested 'and' and 'or' nodes are flattened, double negations
+/// and single-child 'and'/'or' nodes removed.
+/// Does not change the semantics of the tree.
+pub fn plan(cond: CondNode) -> CondNode {
+    let CondNode { children, data } = cond;
+    let mut children = children.into_iter().map(plan).collect::<Vec<_>>();
+    match data {
+        CondNodeType::Not => {
+            let child = children.pop()
+                .expect("Invalid CondNode: 'not' needs a child");
+            if let CondNodeType::Not = child.data {
+                return child.children.into_iter().next()
+                    .expect("Invalid CondNode: 'not' needs a child");
             }
-            print!(")");
-        }, &CondNodeType::Or => {
-            print!("(");
-            let mut first = true;
-            for child in &cond.children {
-                if first {
-                    first = false;
+
+            CondNode { children: vec!(child), data: CondNodeType::Not }
+        }, CondNodeType::Cond(cond) => CondNode::new(CondNodeType::Cond(cond)),
+        data => {
+            let is_and = matches!(data, CondNodeType::And);
+            let mut flat = Vec::new();
+            for child in children.drain(..) {
+                let same = match child.data {
+                    CondNodeType::And => is_and,
+                    CondNodeType::Or => !is_and,
+                    _ => false,
+                };
+
+                if same {
+                    flat.extend(child.children);
                 } else {
-                    print!(" || ");
+                    flat.push(child);
                 }
-                print_cond(&child);
             }
-            print!(")");
-        }, &CondNodeType::Cond(ref cond) => {
-            match &cond.cond_type {
-                &CondType::Exists =>
-                    print!("exists({})", &cond.entry),
-                &CondType::Equals(ref value) =>
-                    print!("({} == {})", &cond.entry, value),
-                &CondType::Matches(ref vals) => {
-                    print!("({} matches [", &cond.entry);
-                    let mut first = true;
-                    for v in vals {
-                        if first {
-                            first = false;
-                        } else {
-                            print!(", ");
-                        }
-
-                        match v {
-                            &MatchString::String(ref a) => print!("{}", a),
-                            &MatchString::Match(ref a) =>
-                                print!("<{}>", a.as_str()),
-                        }
+
+            // stable, conditions with the same cost keep their order
+            flat.sort_by_key(cost);
+            if flat.len() == 1 {
+                flat.pop().unwrap()
+            } else {
+                CondNode { children: flat, data }
+            }
+        }
+    }
+}
+
+// Writes the given value so that it is parsed back as the same value,
+// i.e. quotes and escapes it if needed.
+fn write_value(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    let special = |c: char| "|;,()\"\\<>:=?!~%".contains(c);
+    if value.is_empty() || value.contains(special) ||
+            value.trim() != value {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+        write!(f, "\"{}\"", escaped)
+    } else {
+        write!(f, "{}", value)
+    }
+}
+
+impl fmt::Display for MatchString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MatchString::Match(ref regex) =>
+                write!(f, "<{}>", regex.as_str().replace('>', "\\>")),
+            MatchString::String(ref value) => write_value(f, value),
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.entry)?;
+        let (op, value) = match self.cond_type {
+            CondType::Exists => return Ok(()),
+            CondType::Type(ref t) => return write!(f, "?{}", t),
+            CondType::Matches(ref values) => {
+                write!(f, ":")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
                     }
+                    write!(f, "{}", value)?;
+                }
+                return Ok(());
+            }, CondType::Equals(ref values) |
+                    CondType::EqualsUnordered(ref values) => {
+                let op = match self.cond_type {
+                    CondType::EqualsUnordered(_) => "*=",
+                    _ => "=",
+                };
+
+                write!(f, "{}", op)?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_value(f, value)?;
+                }
+                return Ok(());
+            },
+            CondType::Greater(ref value) => (">", value),
+            CondType::Smaller(ref value) => ("<", value),
+            CondType::Insensitive(ref value) => ("~", value),
+            CondType::Fuzzy(ref value) => ("%", value),
+        };
+
+        write!(f, "{}", op)?;
+        write_value(f, value)
+    }
+}
+
+/// Formats the condition tree as pattern, in a way that parsing
+/// it again results in the same tree.
+impl fmt::Display for CondNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // whether the given child has to be put in parentheses
+        let parens = |child: &CondNode| match (&self.data, &child.data) {
+            (_, &CondNodeType::Cond(_)) => false,
+            (&CondNodeType::And, &CondNodeType::And) => true,
+            (&CondNodeType::And, _) => false,
+            (&CondNodeType::Or, &CondNodeType::Not) => false,
+            _ => true,
+        };
 
-                    print!("])");
-                } _ => print!("<not implemented>"),
+        let sep = match self.data {
+            CondNodeType::Cond(ref cond) => return write!(f, "{}", cond),
+            CondNodeType::Not => {
+                write!(f, "!")?;
+                ""
+            }, CondNodeType::And => ";",
+            CondNodeType::Or => "|",
+        };
+
+        for (i, child) in self.children.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{}", sep)?;
+            }
+
+            if parens(child) {
+                write!(f, "({})", child)?;
+            } else {
+                write!(f, "{}", child)?;
             }
         }
+
+        Ok(())
+    }
+}
+
+impl FromStr for CondNode {
+    type Err = PatternError;
+    fn from_str(s: &str) -> Result<CondNode, PatternError> {
+        parse_condition(s)
+    }
+}
+
+/// Error that occurred while parsing a pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    /// The pattern that could not be parsed.
+    pub pattern: String,
+    /// The byte offset in the pattern at which parsing failed.
+    pub offset: usize,
+    /// Describes what was expected at the offset.
+    pub expected: String,
+}
+
+impl PatternError {
+    /// Renders the pattern with a caret below the error position.
+    pub fn render(&self) -> String {
+        let offset = match self.pattern.get(..self.offset) {
+            Some(a) => a.chars().count(),
+            None => self.offset,
+        };
+
+        format!("{}\n{:>w$}", self.pattern, "^", w = offset + 1)
+    }
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {} at position {}", self.expected, self.offset)
+    }
+}
+
+impl error::Error for PatternError {
+    fn description(&self) -> &str {
+        "invalid pattern"
     }
 }
 
 // parser
-named!(identifier<&str>, map_res!(is_not!(":=<>;|()"), str::from_utf8));
-
-named!(value_string_unesc, is_not!("|;,()"));
-named!(value_string_esc,
-    delimited!(tag!("\""), take_until!("\""), tag!("\"")));
-named!(value_string<&str>, map_res!(
-    alt_complete!(value_string_unesc | value_string_esc),
-    str::from_utf8));
-
-named!(value_pattern<&str>, map_res!(
-    delimited!(tag!("<"), take_until!(">"), tag!(">")),
-    str::from_utf8));
-named!(value_string_or_pattern<MatchString>, alt_complete!(
-    map!(map_res!(value_pattern, Regex::new), MatchString::Match) |
-    map!(map!(value_string, ToString::to_string), MatchString::String)));
-
-// TODO: date or number for greater/smaller
+fn is_space(c: u8) -> bool {
+    c.is_ascii_whitespace()
+}
+
+fn trim_end(s: &str) -> &str {
+    s.trim_end()
+}
+
+named!(space, take_while!(is_space));
+
+// Parses a field name. A '*' is part of it (e.g. 'tasks.*.done'),
+// unless it starts the '*=' operator.
+fn identifier(input: &[u8]) -> IResult<&[u8], &str> {
+    let end = input.iter().enumerate().position(|(i, &c)|
+        b":=<>?~%;|()".contains(&c) ||
+        (c == b'*' && input.get(i + 1) == Some(&b'=')))
+        .unwrap_or(input.len());
+    if end == 0 {
+        return IResult::Error(ErrorKind::IsNot);
+    }
+
+    match str::from_utf8(&input[..end]) {
+        Ok(name) => IResult::Done(&input[end..], trim_end(name)),
+        Err(_) => IResult::Error(ErrorKind::MapRes),
+    }
+}
+
+// Parses a value enclosed by open and close, in which a backslash
+// escapes the closing delimiter. If unescape is set, all other escaped
+// characters are resolved as well, otherwise only the backslash
+// before the closing delimiter is removed (used for regular
+// expressions which have their own escape sequences).
+fn delimited_value(input: &[u8], open: u8, close: u8, unescape: bool)
+        -> IResult<&[u8], String> {
+    if input.first() != Some(&open) {
+        return IResult::Error(ErrorKind::Tag);
+    }
+
+    let mut value = Vec::new();
+    let mut escaped = false;
+    for (i, &c) in input.iter().enumerate().skip(1) {
+        if escaped {
+            if c != close && !unescape {
+                value.push(b'\\');
+            }
+            value.push(c);
+            escaped = false;
+        } else if c == b'\\' {
+            escaped = true;
+        } else if c == close {
+            return match String::from_utf8(value) {
+                Ok(v) => IResult::Done(&input[i + 1..], v),
+                Err(_) => IResult::Error(ErrorKind::MapRes),
+            };
+        } else {
+            value.push(c);
+        }
+    }
+
+    IResult::Error(ErrorKind::Escaped)
+}
+
+fn value_string_esc(input: &[u8]) -> IResult<&[u8], String> {
+    delimited_value(input, b'"', b'"', true)
+}
+
+fn value_pattern(input: &[u8]) -> IResult<&[u8], String> {
+    delimited_value(input, b'<', b'>', false)
+}
+
+named!(value_string_unesc<String>, map!(map!(map_res!(
+    verify!(is_not!("|;,()\""), |v: &[u8]| !v.is_empty()),
+    str::from_utf8), trim_end), ToString::to_string));
+named!(value_string<String>, alt_complete!(
+    value_string_esc | value_string_unesc));
+
+// a value starting with '<' is always a regular expression
+fn value_string_or_pattern(input: &[u8]) -> IResult<&[u8], MatchString> {
+    if input.first() == Some(&b'<') {
+        map!(input, map_res!(value_pattern, |p: String| Regex::new(&p)),
+            MatchString::Match)
+    } else {
+        map!(input, value_string, MatchString::String)
+    }
+}
+
+fn type_name(name: String) -> Result<String, ()> {
+    if TYPE_NAMES.contains(&name.as_str()) {
+        Ok(name)
+    } else {
+        Err(())
+    }
+}
+
 named!(cond_value<CondType>, switch!(
-    opt!(alt_complete!(
+    opt!(preceded!(space, alt_complete!(
         tag!(":") |
         tag!("=") |
+        tag!("*=") |
         tag!(">") |
-        tag!("<"))),
+        tag!("<") |
+        tag!("~") |
+        tag!("%") |
+        tag!("?")))),
     Some(b":") => map!(
         separated_nonempty_list_complete!(
-            tag!(","),
-            value_string_or_pattern),
+            preceded!(space, tag!(",")),
+            preceded!(space, value_string_or_pattern)),
         CondType::Matches) |
     Some(b"=") => map!(
-        map!(value_string, ToString::to_string),
-        CondType::Equals)  |
+        separated_nonempty_list_complete!(
+            preceded!(space, tag!(",")),
+            preceded!(space, value_string)),
+        CondType::Equals) |
+    Some(b"*=") => map!(
+        separated_nonempty_list_complete!(
+            preceded!(space, tag!(",")),
+            preceded!(space, value_string)),
+        CondType::EqualsUnordered) |
     Some(b">") => map!(
-        map!(value_string, ToString::to_string),
+        preceded!(space, value_string),
         CondType::Greater) |
     Some(b"<") => map!(
-        map!(value_string, ToString::to_string),
-        CondType::Smaller) /* |
-    NOTE: enable this to allow exist statements
+        preceded!(space, value_string),
+        CondType::Smaller) |
+    Some(b"~") => map!(
+        preceded!(space, value_string),
+        CondType::Insensitive) |
+    Some(b"%") => map!(
+        preceded!(space, value_string),
+        CondType::Fuzzy) |
+    Some(b"?") => map!(
+        map_res!(preceded!(space, value_string), type_name),
+        CondType::Type) |
     None => value!(CondType::Exists)
-    */
-));
-named!(expr<CondNode>, alt_complete!(
-    delimited!(tag!("("), and, tag!(")")) |
-    do_parse!(
-        entry: identifier >>
-        cond: cond_value >>
-        (CondNode::new(CondNodeType::Cond(Cond {
-            entry: entry.to_string(),
-            cond_type: cond,
-        })))
-    )
 ));
 
-named!(not<CondNode>, alt_complete!(map!(
-        preceded!(tag!("!"), expr),
-        |expr| CondNode {
-            children: vec!(expr),
-            data: CondNodeType::Not
-        }) | expr));
+/// Definitions that can be used in patterns, usually from the config
+/// (see Config::definitions).
+#[derive(Default)]
+pub struct Definitions {
+    /// Field aliases, mapping alias to field name.
+    pub aliases: HashMap<String, String>,
+    /// Saved queries, mapping name to pattern. They are referenced as
+    /// '@name' in patterns, like a pseudo field without operator.
+    pub queries: HashMap<String, String>,
+    /// Whether arrays are compared order-insensitive in equality
+    /// conditions, e.g. whether 'tags=a,b' matches tags ['b', 'a'].
+    /// Single conditions can always use '*=' for that.
+    pub unordered_arrays: bool,
+}
 
-named!(or<CondNode>, map!(
-    separated_nonempty_list_complete!(tag!("|"), not),
-    |mut children| {
-        if children.len() == 1 {
-            children.pop().unwrap()
-        } else {
-            CondNode {
-                children,
-                data: CondNodeType::Or,
-            }
+/// Resolves the given field name using the given aliases (mapping alias
+/// to field name). Either the full name or its first component can be
+/// an alias, e.g. with 'p = "project"', 'p.client' resolves to
+/// 'project.client'. Pseudo fields are never resolved.
+pub fn resolve_alias(name: &str, aliases: &HashMap<String, String>)
+        -> String {
+    if name.starts_with('@') {
+        return name.to_string();
+    }
+
+    if let Some(field) = aliases.get(name) {
+        return field.clone();
+    }
+
+    match name.find('.') {
+        Some(i) => match aliases.get(&name[..i]) {
+            Some(field) => format!("{}{}", field, &name[i..]),
+            None => name.to_string(),
+        }, None => name.to_string(),
+    }
+}
+
+fn entry_name(name: &str) -> Result<&str, ()> {
+    if name.starts_with('@') && !PSEUDO_FIELDS.contains(&&name[1..]) {
+        Err(())
+    } else {
+        Ok(name)
+    }
+}
+
+// The structure of patterns is parsed by hand (instead of with nom) so
+// that errors can be reported with the exact position they occurred at.
+// Grammar, from lowest to highest precedence:
+//  and ::= or (';' or)*
+//  or ::= not ('|' not)*
+//  not ::= '!' expr | expr
+//  expr ::= '(' and ')' | '@' query | identifier cond_value
+type ParseResult<'a> = Result<(CondNode, &'a [u8]), PatternError>;
+
+struct Parser<'a> {
+    pattern: &'a str,
+    defs: &'a Definitions,
+    // the saved queries currently expanded, to detect cycles
+    expanding: Vec<&'a str>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, rest: &[u8], expected: &str) -> PatternError {
+        PatternError {
+            pattern: self.pattern.to_string(),
+            offset: self.pattern.len() - rest.len(),
+            expected: expected.to_string(),
         }
     }
-));
 
-named!(and<CondNode>, map!(
-    separated_nonempty_list_complete!(tag!(";"), or),
-    |mut children| {
+    fn list(&self, mut input: &'a [u8], sep: u8, data: CondNodeType,
+            elem: fn(&Self, &'a [u8]) -> ParseResult<'a>) -> ParseResult<'a> {
+        let mut children = Vec::new();
+        loop {
+            let (child, rest) = elem(self, input)?;
+            children.push(child);
+            input = skip_space(rest);
+            if input.first() != Some(&sep) {
+                break;
+            }
+
+            input = &input[1..];
+        }
+
         if children.len() == 1 {
-            children.pop().unwrap()
+            Ok((children.pop().unwrap(), input))
         } else {
-            CondNode {
-                children,
-                data: CondNodeType::And,
-            }
+            Ok((CondNode { children, data }, input))
         }
     }
-));
 
-pub fn parse_condition(pattern: &str) -> Result<CondNode, String> {
-    match and(pattern.as_bytes()) {
-        IResult::Done(rest, value) => {
-            if rest.len() > 0 {
-                // TODO: performance
-                let str = match str::from_utf8(rest) {
-                    Ok(a) => a,
-                    Err(_) => return Err("Invalid condition: non-utf8 \
-                        input sequence".to_string()),
-                };
-                Err(format!("Unexpected character {}",
-                    str.chars().next().unwrap()))
-            } else {
-                Ok(value)
+    fn and(&self, input: &'a [u8]) -> ParseResult<'a> {
+        self.list(input, b';', CondNodeType::And, Parser::or)
+    }
+
+    fn or(&self, input: &'a [u8]) -> ParseResult<'a> {
+        self.list(input, b'|', CondNodeType::Or, Parser::not)
+    }
+
+    fn not(&self, input: &'a [u8]) -> ParseResult<'a> {
+        let input = skip_space(input);
+        if input.first() != Some(&b'!') {
+            return self.expr(input);
+        }
+
+        let (child, rest) = self.expr(&input[1..])?;
+        Ok((CondNode { children: vec!(child), data: CondNodeType::Not }, rest))
+    }
+
+    fn expr(&self, input: &'a [u8]) -> ParseResult<'a> {
+        let input = skip_space(input);
+        if input.first() == Some(&b'(') {
+            let (node, rest) = self.and(&input[1..])?;
+            return match rest.first() {
+                Some(&b')') => Ok((node, &rest[1..])),
+                _ => Err(self.error(rest, "';', '|' or ')'")),
+            };
+        }
+
+        let (rest, entry) = match identifier(input) {
+            IResult::Done(rest, entry) => (rest, entry),
+            _ => return Err(self.error(input, "field name, '!' or '('")),
+        };
+
+        let entry = resolve_alias(entry, &self.defs.aliases);
+        if entry_name(&entry).is_err() {
+            if let Some((name, query)) = self.defs.queries
+                    .get_key_value(&entry[1..]) {
+                return self.query(input, name, query, rest);
+            }
+
+            let fields = PSEUDO_FIELDS.iter()
+                .map(|f| format!("@{}", f))
+                .collect::<Vec<_>>();
+            return Err(self.error(input, &format!(
+                "pseudo field ({}) or saved query", fields.join(", "))));
+        }
+
+        let (rest, cond_type) = match cond_value(rest) {
+            IResult::Done(r, cond_type) => (r, cond_type),
+            _ => {
+                // there always is an operator since a missing one
+                // means an existence check
+                let op = skip_space(rest);
+                let len = if op.starts_with(b"*=") { 2 } else { 1 };
+                return Err(self.value_error(op[0], &op[len..]));
+            }
+        };
+
+        // a trailing ',' means the following value is invalid
+        let sep = skip_space(rest);
+        if sep.first() == Some(&b',') {
+            match cond_type {
+                CondType::Matches(_) =>
+                    return Err(self.value_error(b':', &sep[1..])),
+                CondType::Equals(_) | CondType::EqualsUnordered(_) =>
+                    return Err(self.value_error(b'=', &sep[1..])),
+                _ => (),
             }
-        }, IResult::Error(err) =>
-            Err(format!("Parse Error: {}", err)),
-        IResult::Incomplete(needed) =>
-            Err(format!("Incomplete condition. Needed: {:?}", needed)),
+        }
+
+        let cond_type = match cond_type {
+            CondType::Equals(v) if self.defs.unordered_arrays =>
+                CondType::EqualsUnordered(v),
+            cond_type => cond_type,
+        };
+
+        let cond = Cond { entry, cond_type };
+        Ok((CondNode::new(CondNodeType::Cond(cond)), rest))
+    }
+
+    // Expands the saved query with the given name, referenced at input.
+    fn query(&self, input: &'a [u8], name: &'a str, query: &'a str,
+            rest: &'a [u8]) -> ParseResult<'a> {
+        match cond_value(rest) {
+            IResult::Done(_, CondType::Exists) => (),
+            _ => return Err(self.error(skip_space(rest),
+                "';', '|' or ')' after saved query")),
+        }
+
+        if self.expanding.contains(&name) {
+            return Err(self.error(input, &format!(
+                "saved query that does not reference itself ('@{}')", name)));
+        }
+
+        let mut expanding = self.expanding.clone();
+        expanding.push(name);
+        let parser = Parser { pattern: query, defs: self.defs, expanding };
+        match parser.parse() {
+            Ok(node) => Ok((node, rest)),
+            Err(err) => Err(self.error(input, &format!(
+                "valid saved query '@{}' ({})", name, err))),
+        }
+    }
+
+    fn parse(&self) -> Result<CondNode, PatternError> {
+        let (node, rest) = self.and(self.pattern.as_bytes())?;
+        match rest.first() {
+            None => Ok(node),
+            Some(&b')') =>
+                Err(self.error(rest, "end of pattern (unmatched ')')")),
+            Some(_) => Err(self.error(rest, "';', '|' or end of pattern")),
+        }
+    }
+
+    // Returns the error for an invalid value following the given operator.
+    fn value_error(&self, op: u8, value: &[u8]) -> PatternError {
+        let value = skip_space(value);
+        let end = &value[value.len()..];
+        match (op, value.first()) {
+            (b'?', _) => self.error(value, &format!("type name ({})",
+                TYPE_NAMES.join(", "))),
+            (b':', Some(&b'<')) => match value_pattern(value) {
+                IResult::Done(..) => self.error(value, "valid regex"),
+                _ => self.error(end, "closing '>'"),
+            }, (_, Some(&b'"')) => self.error(end, "closing '\"'"),
+            (b':', _) => self.error(value, "value or <regex>"),
+            _ => self.error(value, "value"),
+        }
+    }
+}
+
+fn skip_space(input: &[u8]) -> &[u8] {
+    match space(input) {
+        IResult::Done(rest, _) => rest,
+        _ => input,
+    }
+}
+
+/// Parses the given pattern into a condition tree.
+pub fn parse_condition(pattern: &str) -> Result<CondNode, PatternError> {
+    parse_condition_with(pattern, &Definitions::default())
+}
+
+/// Parses the given pattern into a condition tree, resolving field
+/// aliases and saved queries from the given definitions.
+pub fn parse_condition_with(pattern: &str, defs: &Definitions)
+        -> Result<CondNode, PatternError> {
+    Parser { pattern, defs, expanding: Vec::new() }.parse()
+}
+
+/// Parses a comma-separated list of fields to sort by, like
+/// 'priority,-created' or '@mtime'. Fields prefixed with '-' are
+/// sorted in descending order. Aliases are resolved.
+pub fn parse_sort(sort: &str, defs: &Definitions)
+        -> Result<Vec<SortKey>, PatternError> {
+    let parser = Parser { pattern: sort, defs, expanding: Vec::new() };
+    let mut keys = Vec::new();
+    let mut offset = 0;
+    for part in sort.split(',') {
+        let rest = &sort.as_bytes()[offset..];
+        offset += part.len() + 1;
+
+        let part = part.trim();
+        let (field, descending) = match part.strip_prefix('-') {
+            Some(field) => (field.trim(), true),
+            None => (part, false),
+        };
+
+        if field.is_empty() {
+            return Err(parser.error(skip_space(rest), "field name"));
+        }
+
+        let field = resolve_alias(field, &defs.aliases);
+        if entry_name(&field).is_err() {
+            return Err(parser.error(skip_space(rest), "meta or pseudo field"));
+        }
+
+        keys.push(SortKey { field, descending });
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses the given pattern, panics if it is invalid.
+    fn parse(pattern: &str) -> CondNode {
+        match pattern.parse::<CondNode>() {
+            Ok(a) => a,
+            Err(e) => panic!("Failed to parse '{}': {}", pattern, e),
+        }
+    }
+
+    // Returns the error of parsing the given invalid pattern.
+    fn error(pattern: &str) -> PatternError {
+        match pattern.parse::<CondNode>() {
+            Ok(a) => panic!("Parsed invalid pattern '{}' as '{}'", pattern, a),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn error_offsets() {
+        assert_eq!(error("tags:").offset, 5);
+        assert_eq!(error("tags: a,").offset, 8);
+        assert_eq!(error("tags=a,").offset, 7);
+        assert_eq!(error("tags*=").offset, 6);
+        assert_eq!(error("(a|b").offset, 4);
+        assert_eq!(error("a;;b").offset, 2);
+        assert_eq!(error("a)").offset, 1);
+        assert_eq!(error("a?nothing").offset, 2);
+        assert_eq!(error("@nothing").offset, 0);
+
+        // unterminated values point at the end of the pattern
+        assert_eq!(error("title=\"abc").offset, 10);
+        assert_eq!(error("tags:<abc").offset, 9);
+    }
+
+    #[test]
+    fn error_offsets_multibyte() {
+        let err = error("täg:");
+        assert_eq!(err.offset, 5);
+        assert_eq!(err.render(), "täg:\n    ^");
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let patterns = [
+            "tags",
+            "!tags",
+            "tags:a,b;!archived|@id>5",
+            "a|b;c",
+            "(a;b)|c",
+            "!(a;b)",
+            "tags=a,b",
+            "tags*=a,b",
+            "tags=\"a,b\",c",
+            "title=\"a; b\"",
+            "title=\"say \\\"hi\\\"\"",
+            "title=\" padded \"",
+            "tags:<c\\+\\+>",
+            "tags:<a\\>b>",
+            "c:foo,<ba[rz]>",
+            "project.client~acme",
+            "title%helo",
+            "created<2018-02-10",
+            "priority?int",
+            "tasks.*.done=false",
+            "tasks.*.tags*=a,b",
+        ];
+
+        for pattern in patterns.iter() {
+            let printed = parse(pattern).to_string();
+            assert_eq!(&printed, pattern);
+            assert_eq!(parse(&printed).to_string(), printed);
+        }
+    }
+
+    #[test]
+    fn wildcard_fields() {
+        let cond = match parse("tasks.*.done=false").data {
+            CondNodeType::Cond(cond) => cond,
+            _ => panic!("Expected a single condition"),
+        };
+
+        assert_eq!(cond.entry, "tasks.*.done");
+        match cond.cond_type {
+            CondType::Equals(ref v) => assert_eq!(v, &["false"]),
+            _ => panic!("Expected an equality condition"),
+        }
+
+        match parse("tags *= a").data {
+            CondNodeType::Cond(Cond { ref entry,
+                    cond_type: CondType::EqualsUnordered(_) }) =>
+                assert_eq!(entry, "tags"),
+            _ => panic!("Expected an unordered equality condition"),
+        }
+    }
+
+    #[test]
+    fn display_normalizes() {
+        assert_eq!(parse(" tags : a , b ").to_string(), "tags:a,b");
+        assert_eq!(parse("((a))").to_string(), "a");
+        assert_eq!(parse("(a|b);c").to_string(), "a|b;c");
+        assert_eq!(parse("a;(b;c)").to_string(), "a;(b;c)");
+        assert_eq!(parse("a|(b|c)").to_string(), "a|(b|c)");
+    }
+
+    #[test]
+    fn escaped_values() {
+        let cond = |node: CondNode| match node.data {
+            CondNodeType::Cond(cond) => cond,
+            _ => panic!("Expected a single condition"),
+        };
+
+        match cond(parse("title=\"a; b\"")).cond_type {
+            CondType::Equals(ref v) => assert_eq!(v, &["a; b"]),
+            _ => panic!("Expected an equality condition"),
+        }
+
+        match cond(parse("tags=\"a,b\",c")).cond_type {
+            CondType::Equals(ref v) => assert_eq!(v, &["a,b", "c"]),
+            _ => panic!("Expected an equality condition"),
+        }
+
+        match cond(parse("tags:<c\\+\\+>")).cond_type {
+            CondType::Matches(ref v) => match v[..] {
+                [MatchString::Match(ref r)] => {
+                    assert_eq!(r.as_str(), "c\\+\\+");
+                    assert!(r.is_match("c++"));
+                }, _ => panic!("Expected a single regex"),
+            },
+            _ => panic!("Expected a match condition"),
+        }
     }
 }
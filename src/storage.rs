@@ -1,26 +1,155 @@
 use super::toml;
 use super::config::Config;
 use super::node::Node;
+use super::pattern;
+use super::index::{ContentIndex, MetaCache};
+use super::file;
+use super::error::Error;
 use std::io;
 use std::fs;
 
 use std::io::prelude::*;
-use std::path::PathBuf;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::cmp;
+use std::cmp::Ordering;
+use fs2::FileExt;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+// Version of the storage layout, storages of older versions are
+// migrated when loaded, see Storage::migrate.
+const STATE_VERSION: u32 = 1;
 
 #[derive(Deserialize, Serialize)]
 pub struct StorageState {
     last_id: u64,
     last_edited: u64, // edited
     last_viewed: u64, // only shown but not edited
-    last: u64 // general last interaction with specific node
+    last: u64, // general last interaction with specific node
+    #[serde(default)]
+    version: u32, // see STATE_VERSION, missing in the first version
+}
+
+/// Guard for the advisory lock of a storage, see Storage::lock.
+/// Releases the lock when dropped.
+pub struct StorageLock {
+    file: File,
 }
 
 pub struct Storage<'a> {
     config: &'a Config,
     name: String,
     path: PathBuf,
-    state: StorageState,
+    state: RefCell<StorageState>,
+    dirty: Cell<bool>, // whether state has changes not yet saved
+}
+
+/// A node whose id is reserved but that was not yet committed,
+/// see Storage::reserve.
+/// Dropping it without committing rolls the node back, i.e. removes
+/// its files and releases its id if possible. Since reserving skips
+/// ids that already have files, only files created for this node
+/// are removed.
+pub struct NextNode<'a, 'b: 'a> {
+    storage: &'a Storage<'b>,
+    id: u64,
+    committed: bool,
+}
+
+/// Describes which nodes to list and in which order, see Storage::query.
+#[derive(Default)]
+pub struct Query {
+    /// Only list nodes matching this condition.
+    pub cond: Option<pattern::CondNode>,
+    /// List archived instead of active nodes.
+    pub archived: bool,
+    /// Sort by these fields. Ties are sorted by id.
+    pub sort: Vec<pattern::SortKey>,
+    /// Sort by relevance for the condition first, see pattern::score.
+    pub rank: bool,
+    /// Reverse the order.
+    pub reverse: bool,
+    /// How many nodes to skip.
+    pub offset: usize,
+    /// The maximum number of nodes to return, all if None.
+    pub limit: Option<usize>,
+}
+
+/// The result of a query: a page of the matching nodes.
+pub struct Page<'a, 'b: 'a> {
+    /// The nodes on this page, in order.
+    pub nodes: Vec<Node<'a, 'b>>,
+    /// The total number of matching nodes.
+    pub total: usize,
+}
+
+// A node matching a query, see Storage::matches.
+struct Match<'a, 'b: 'a> {
+    node: Node<'a, 'b>,
+    score: f64,
+    keys: Vec<Option<toml::Value>>,
+}
+
+// Sorts the given matches as described by the query and returns
+// the requested page.
+fn page<'a, 'b>(mut nodes: Vec<Match<'a, 'b>>, query: &Query) -> Page<'a, 'b> {
+    nodes.sort_by(|a, b| {
+        let score = if query.rank {
+            a.score.partial_cmp(&b.score).unwrap_or(Ordering::Equal)
+        } else {
+            Ordering::Equal
+        };
+
+        score.then_with(|| pattern::compare_keys(&query.sort, &a.keys, &b.keys))
+            .then_with(|| a.node.id().cmp(&b.node.id()))
+            .then_with(|| a.node.storage().name().cmp(b.node.storage().name()))
+    });
+
+    if query.reverse {
+        nodes.reverse();
+    }
+
+    let total = nodes.len();
+    let limit = query.limit.unwrap_or(total);
+    let nodes = nodes.into_iter().skip(query.offset).take(limit)
+        .map(|n| n.node).collect();
+    Page { nodes, total }
+}
+
+/// Like Storage::query but returns the matching nodes of all given
+/// storages, ordered (and paged) together. Ties are sorted by
+/// id and then storage name.
+pub fn query_all<'a>(storages: &'a [Storage<'a>], query: &Query)
+        -> Page<'a, 'a> {
+    let nodes = storages.iter().flat_map(|s| s.matches(query)).collect();
+    page(nodes, query)
+}
+
+/// Lazily enumerates the nodes of a storage, see Storage::nodes.
+/// Entries that can't be read or aren't nodes yield errors, which don't
+/// end the iteration. Hidden and temporary files are skipped.
+pub struct NodeIter<'a, 'b: 'a> {
+    storage: &'a Storage<'b>,
+    dirs: Vec<(PathBuf, bool)>, // node directories still to enumerate
+    current: Option<(fs::ReadDir, bool)>,
+}
+
+/// Only yields the nodes matching a condition, see NodeIter::matching.
+pub struct Matching<'a, 'b: 'a, 'c> {
+    nodes: NodeIter<'a, 'b>,
+    cond: &'c pattern::CondNode,
+}
+
+/// Error enumerating the nodes of a storage.
+#[derive(Debug)]
+pub enum EnumerateError {
+    /// A nodes directory could not be read.
+    ReadDir(PathBuf, io::Error),
+    /// An entry of a nodes directory could not be read.
+    Entry(io::Error),
+    /// A file in a nodes directory is not named after a node id.
+    InvalidName(PathBuf),
 }
 
 #[derive(Debug)]
@@ -33,41 +162,248 @@ pub enum LoadStorageError {
 }
 
 impl<'a> Storage<'a> {
+    /// Creates a new, empty storage at the given path, i.e. the storage
+    /// file and the nodes and meta directories. The path itself is
+    /// created if needed.
+    /// Refuses to overwrite existing data: fails with
+    /// io::ErrorKind::AlreadyExists if there already is a storage file,
+    /// nodes or meta directory at the given path. This also holds when
+    /// storages are created concurrently, only the entries created
+    /// by this call are removed on failure.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+
+        // create_dir and create_new fail if the entry already exists
+        let nodes = path.join("nodes");
+        let archive = nodes.join("archive");
+        let meta = path.join("meta");
+        fs::create_dir(&nodes)?;
+        let res = fs::create_dir(&archive).and_then(|_| {
+            fs::create_dir(&meta).inspect_err(|_| {
+                let _ = fs::remove_dir(&archive);
+            })
+        });
+
+        if let Err(e) = res {
+            let _ = fs::remove_dir(&nodes);
+            return Err(e);
+        }
+
+        let state = StorageState {
+            last_id: 0,
+            last_edited: 0,
+            last_viewed: 0,
+            last: 0,
+            version: STATE_VERSION,
+        };
+
+        let state = toml::to_string(&state)
+            .expect("Internal error, serializing state file");
+        let state_path = path.join("storage");
+        let res = OpenOptions::new().write(true).create_new(true)
+            .open(&state_path)
+            .and_then(|mut f| f.write_all(state.as_bytes()).inspect_err(|_| {
+                let _ = fs::remove_file(&state_path);
+            }));
+
+        if res.is_err() {
+            let _ = fs::remove_dir(&meta);
+            let _ = fs::remove_dir(&archive);
+            let _ = fs::remove_dir(&nodes);
+        }
+
+        res
+    }
+
     /// Loads the storage for the given stoage path.
     /// Note that the passed path has to be the base path of the storage,
     /// not the storage file itself.
     pub fn load(config: &'a Config, name: &str, path: PathBuf)
-            -> Result<Storage<'a>, LoadStorageError> {
-        let mut spath = path.clone();
-        spath.push("storage");
-        let mut f = match File::open(spath) {
-            Ok(f) => f,
-            Err(e) => return Err(LoadStorageError::Open(e)),
+            -> Result<Storage<'a>, Error> {
+        let state = match read_state(&path) {
+            Ok(a) => RefCell::new(a),
+            Err(cause) => return Err(Error::LoadStorage {
+                storage: name.to_string(),
+                cause,
+            }),
         };
 
-        let mut s = String::new();
-        if let Err(e) = f.read_to_string(&mut s) {
-            return Err(LoadStorageError::Read(e));
+        Ok(Storage { config, name: name.to_string(), path, state,
+            dirty: Cell::new(false) })
+    }
+
+    // Brings a storage of an older version up to date, only done once.
+    // Version 1 records the archived state in the meta files.
+    // Must be called with the storage lock held.
+    fn migrate(&self) -> Result<(), Error> {
+        let mut state = self.load_state()?;
+        if state.version < 1 {
+            for node in self.all_nodes() {
+                let mut node = match node {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("{}", Error::Enumerate {
+                            storage: self.name.clone(),
+                            cause: e,
+                        });
+                        continue;
+                    },
+                };
+
+                let meta = match node.load_meta() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("{}", Error::Meta {
+                            id: node.id(),
+                            path: node.meta_path(),
+                            cause: e,
+                        });
+                        continue;
+                    },
+                };
+
+                let archived = node.archived();
+                if meta.get("archived").and_then(|v| v.as_bool())
+                        != Some(archived) {
+                    node.patch_meta(&[("archived", Some(archived.into()))])?;
+                }
+            }
+
+            info!("Recorded archived state of nodes in storage {}",
+                self.name);
         }
 
-        let state = match toml::from_str::<StorageState>(&s) {
-            Ok(s) => s,
-            Err(e) => return Err(LoadStorageError::Parse(e)),
-        };
+        state.version = STATE_VERSION;
+        self.store_state(&state)?;
+        self.state.borrow_mut().version = STATE_VERSION;
+        Ok(())
+    }
+
+    /// Takes the advisory lock of this storage, blocks until it is
+    /// available. Operations modifying the storage (reserving ids, saving
+    /// the state, removing or archiving nodes) take it while they run,
+    /// other tools modifying the storage should do the same.
+    /// The lock is not reentrant: while holding it, none of those
+    /// operations may be called.
+    /// Storages of an older version are migrated when they are locked
+    /// the first time, i.e. before they are modified.
+    pub fn lock(&self) -> Result<StorageLock, Error> {
+        let path = self.path.join("lock");
+        let lock = OpenOptions::new()
+            .write(true).create(true).truncate(false)
+            .open(&path)
+            .and_then(|file| file.lock_exclusive().map(|_| file))
+            .map(|file| StorageLock { file })
+            .map_err(|e| self.file_error(path, e))?;
 
-        Ok(Storage { config, name: name.to_string(), path, state })
+        if self.state.borrow().version < STATE_VERSION {
+            if let Err(e) = self.migrate() {
+                warn!("{}", e);
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// Reserves a new node id, i.e. increases the id counter on disk
+    /// and returns the new id. This happens under the storage lock and
+    /// is therefore safe to be used concurrently by multiple processes,
+    /// no id will be returned twice.
+    /// Ids that already have a node or meta file are skipped, in case
+    /// the id counter is outdated.
+    /// Usually Storage::reserve should be used instead.
+    pub fn reserve_id(&self) -> Result<u64, Error> {
+        let _lock = self.lock()?;
+        let mut state = self.load_state()?;
+        state.last_id += 1;
+        while self.id_used(state.last_id) {
+            warn!("Storage {}: id {} is already used, skipping it",
+                self.name, state.last_id);
+            state.last_id += 1;
+        }
+
+        state.last = state.last_id;
+        self.store_state(&state)?;
+
+        let mut current = self.state.borrow_mut();
+        current.last_id = state.last_id;
+        current.last = state.last_id;
+        Ok(state.last_id)
+    }
+
+    /// Reserves a new node id (see reserve_id) and returns a handle to
+    /// write the new node. The node is only kept if it is committed.
+    pub fn reserve(&self) -> Result<NextNode, Error> {
+        let id = self.reserve_id()?;
+        Ok(NextNode { storage: self, id, committed: false })
+    }
+
+    // Returns whether there is a node file (active or archived) or meta
+    // file for the given id.
+    fn id_used(&self, id: u64) -> bool {
+        let active = Node::new_archived(self, id, false);
+        let archived = Node::new_archived(self, id, true);
+        [active.node_path(), archived.node_path(), active.meta_path()]
+            .iter().any(|p| fs::symlink_metadata(p).is_ok())
     }
 
-    /// Returns the next id that would be used for a node.
-    /// Does not automatically increase it, see use_id.
-    pub fn next_id(&self) -> u64 {
-        self.state.last_id + 1
+    // Returns an error for accessing the given file of this storage.
+    fn file_error(&self, path: PathBuf, cause: io::Error) -> Error {
+        Error::Storage { storage: self.name.clone(), path, cause }
     }
 
-    /// Uses the current next_id, i.e. increases the id counter.
-    pub fn use_id(&mut self) {
-        self.state.last_id += 1;
-        self.state.last = self.state.last_id;
+    // Reads the state file of this storage.
+    fn load_state(&self) -> Result<StorageState, Error> {
+        read_state(&self.path).map_err(|e|
+            self.file_error(self.path.join("storage"), state_io_error(e)))
+    }
+
+    // Writes the given state to the state file of this storage.
+    fn store_state(&self, state: &StorageState) -> Result<(), Error> {
+        write_state(&self.path, state)
+            .map_err(|e| self.file_error(self.path.join("storage"), e))
+    }
+
+    // Gives back the given id reserved by reserve_id, which is only
+    // possible if no later id was reserved in the meantime.
+    fn release_id(&self, id: u64) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        let mut state = self.load_state()?;
+        if state.last_id != id {
+            return Ok(());
+        }
+
+        state.last_id = id - 1;
+        if state.last == id {
+            state.last = id - 1;
+        }
+
+        self.store_state(&state)?;
+        let mut current = self.state.borrow_mut();
+        current.last_id = state.last_id;
+        current.last = state.last;
+        Ok(())
+    }
+
+    /// Writes the storage state (e.g. the last used id) to disk if it
+    /// was changed. The state file is replaced atomically, i.e. it
+    /// stays intact if writing fails midway.
+    /// Should be called after changing the state, dropping the storage
+    /// only saves it as a fallback and can't report errors.
+    pub fn save(&mut self) -> Result<(), Error> {
+        if !self.dirty.get() {
+            return Ok(());
+        }
+
+        // other processes might have reserved ids in the meantime
+        let _lock = self.lock()?;
+        let disk = self.load_state()?;
+        let mut state = self.state.borrow_mut();
+        state.last_id = cmp::max(state.last_id, disk.last_id);
+        self.store_state(&state)?;
+        self.dirty.set(false);
+        Ok(())
     }
 
     /// Returns the path of this storage
@@ -92,118 +428,430 @@ impl<'a> Storage<'a> {
         &self.name
     }
 
-    /// Returns a list of all nodes in this storage.
-    pub fn nodes(&self) -> Vec<Node> {
-        self.list_nodes(&self.nodes_path(), false)
+    /// Returns an iterator over all (not archived) nodes in this storage.
+    pub fn nodes(&self) -> NodeIter {
+        NodeIter::new(self, vec!((self.nodes_path(), false)))
     }
 
-    /// Returns a list of all nodes in this storage.
-    pub fn archived(&self) -> Vec<Node> {
-        let mut path = self.nodes_path();
-        path.push("archive");
-        self.list_nodes(&path, true)
+    /// Returns an iterator over all archived nodes in this storage.
+    pub fn archived(&self) -> NodeIter {
+        NodeIter::new(self, vec!((self.nodes_path().join("archive"), true)))
+    }
+
+    /// Returns an iterator over all nodes in this storage, first the
+    /// active and then the archived ones.
+    pub fn all_nodes(&self) -> NodeIter {
+        NodeIter::new(self, vec!((self.nodes_path(), false),
+            (self.nodes_path().join("archive"), true)))
+    }
+
+    /// Returns all nodes (or all archived nodes) with their parsed
+    /// meta values. Uses (and updates) the meta cache of this storage,
+    /// so only meta files that changed since the last call are read.
+    pub fn nodes_meta(&self, archived: bool) -> Vec<(Node, toml::Value)> {
+        let mut cache = match MetaCache::load(self) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                warn!("{}", self.file_error(MetaCache::path_for(self), e));
+                None
+            }
+        };
+
+        let list = if archived { self.archived() } else { self.nodes() };
+        let mut ret = Vec::new();
+        for node in list {
+            let node = match node {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("{}", Error::Enumerate {
+                        storage: self.name.clone(),
+                        cause: e,
+                    });
+                    continue;
+                },
+            };
+
+            let meta = match cache {
+                Some(ref mut cache) => cache.get(&node).cloned(),
+                None => node.load_meta(),
+            };
+
+            match meta {
+                Ok(meta) => ret.push((node, meta)),
+                Err(e) => warn!("{}", Error::Meta {
+                    id: node.id(),
+                    path: node.meta_path(),
+                    cause: e,
+                }),
+            }
+        }
+
+        if let Some(mut cache) = cache {
+            // drop entries of removed nodes
+            let ids = ret.iter().map(|n| n.0.id()).collect::<HashSet<_>>();
+            cache.prune(self, &ids);
+
+            if let Err(e) = cache.save() {
+                warn!("{}", self.file_error(MetaCache::path_for(self), e));
+            }
+        }
+
+        ret
+    }
+
+    /// Returns the nodes matching the given query, in the order it
+    /// describes. Since ties are sorted by id, the order is stable and
+    /// the matching nodes can be paged through using offset and limit.
+    /// Uses (and updates) the content index for content queries and
+    /// the meta cache.
+    /// See query_all for querying multiple storages at once.
+    pub fn query(&self, query: &Query) -> Page {
+        page(self.matches(query), query)
+    }
+
+    // Returns all nodes matching the given query, with their score
+    // and sort keys.
+    fn matches(&self, query: &Query) -> Vec<Match> {
+        // content queries use (and update) the content index
+        let index = match query.cond {
+            Some(ref cond) if pattern::uses_content(cond) => {
+                let index = ContentIndex::load(self).and_then(|mut index| {
+                    index.refresh(self)?;
+                    index.save()?;
+                    Ok(index)
+                });
+
+                match index {
+                    Ok(a) => Some(a),
+                    Err(e) => {
+                        warn!("{}", self.file_error(
+                            ContentIndex::path_for(self), e));
+                        None
+                    }
+                }
+            }, _ => None,
+        };
+
+        // meta is only loaded (through the cache) for nodes that need it
+        let cache = if query.cond.is_none() && query.sort.is_empty() {
+            None
+        } else {
+            match MetaCache::load(self) {
+                Ok(a) => Some(RefCell::new(a)),
+                Err(e) => {
+                    warn!("{}", self.file_error(MetaCache::path_for(self), e));
+                    None
+                }
+            }
+        };
+
+        let list = if query.archived { self.archived() } else { self.nodes() };
+        let mut existing = HashSet::new();
+        let mut nodes = Vec::new();
+        for node in list {
+            let node = match node {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("{}", Error::Enumerate {
+                        storage: self.name.clone(),
+                        cause: e,
+                    });
+                    continue;
+                },
+            };
+
+            existing.insert(node.id());
+
+            let (score, keys) = {
+                let mut meta_node = pattern::MetaNode::new(&node);
+                meta_node.index = index.as_ref();
+                meta_node.cache = cache.as_ref();
+
+                let mut score = 0.0;
+                if let Some(ref cond) = query.cond {
+                    if !pattern::node_matches(&meta_node, cond) {
+                        continue;
+                    }
+
+                    if query.rank {
+                        score = pattern::score(&meta_node, cond);
+                    }
+                }
+
+                let keys = query.sort.iter()
+                    .map(|k| pattern::field_value(&meta_node, &k.field))
+                    .collect::<Vec<_>>();
+                (score, keys)
+            };
+
+            nodes.push(Match { node, score, keys });
+        }
+
+        if let Some(cache) = cache {
+            let mut cache = cache.into_inner();
+            cache.prune(self, &existing);
+            if let Err(e) = cache.save() {
+                warn!("{}", self.file_error(MetaCache::path_for(self), e));
+            }
+        }
+
+        nodes
     }
 
     /// Updates the last edited field
     pub fn edited(&mut self, id: u64) {
-        self.state.last_edited = id;
-        self.state.last = id;
+        let state = self.state.get_mut();
+        state.last_edited = id;
+        state.last = id;
+        self.dirty.set(true);
     }
 
     /// Updates the last viewed field
     pub fn viewed(&mut self, id: u64) {
-        self.state.last_viewed = id;
-        self.state.last = id;
+        let state = self.state.get_mut();
+        state.last_viewed = id;
+        state.last = id;
+        self.dirty.set(true);
     }
 
     /// Tries to interpret the given string as node name/id.
-    /// Returns the referenced node on success. Will return an error
-    /// if the given node doesn't exist.
-    pub fn parse(&self, name: &str) -> Result<Node, String> {
+    /// Returns the referenced node on success. Fails with
+    /// Error::InvalidId if it isn't an id and Error::NoNode if the
+    /// given node doesn't exist.
+    pub fn parse(&self, name: &str) -> Result<Node, Error> {
         // last created
+        let state = self.state.borrow();
         let id = match name {
-            "lc" => self.state.last_id,
-            "le" => self.state.last_edited,
-            "lv" => self.state.last_viewed,
-            "l" => self.state.last,
+            "lc" => state.last_id,
+            "le" => state.last_edited,
+            "lv" => state.last_viewed,
+            "l" => state.last,
             _ => match name.parse::<u64>() {
-                Err(_) => return Err(format!("Could not parse '{}' as id", name)),
+                Err(_) => return Err(Error::InvalidId {
+                    storage: self.name.clone(),
+                    name: name.to_string(),
+                }),
                 Ok(i) => i,
             }
         };
 
-        let node = Node::new(&self, id);
+        let node = Node::new(self, id);
         if !node.exists() {
-            return Err(format!("Node {} doesn't exist", id));
+            return Err(Error::NoNode { storage: self.name.clone(), id });
         }
 
         Ok(node)
     }
+}
+
+impl<'a, 'b> NodeIter<'a, 'b> {
+    // Enumerates the given node directories in order. The flag
+    // signals whether they contain archived nodes.
+    fn new(storage: &'a Storage<'b>, mut dirs: Vec<(PathBuf, bool)>)
+            -> NodeIter<'a, 'b> {
+        dirs.reverse(); // we pop from the back
+        NodeIter { storage, dirs, current: None }
+    }
 
-    // TODO: id inc should probably be compile-time checked
-    // should be possible somehow
-    pub fn next_node(&self) -> Node {
-        Node::new(self, self.next_id())
+    /// Only yields the nodes matching the given condition.
+    /// Errors are passed through.
+    /// Meta and content of the nodes are read directly, without using
+    /// the meta cache and content index, see Storage::query for that.
+    pub fn matching<'c>(self, cond: &'c pattern::CondNode)
+            -> Matching<'a, 'b, 'c> {
+        Matching { nodes: self, cond }
     }
+}
 
-    // TODO: should probably return iterator?
-    fn list_nodes<'b>(&'b self, path: &PathBuf, archived: bool) -> Vec<Node<'a, 'b>> {
-        let dir = match fs::read_dir(path) {
-            Ok(a) => a,
-            Err(_) => {
-                return Vec::new();
-            },
-        };
+impl<'a, 'b> Iterator for NodeIter<'a, 'b> {
+    type Item = Result<Node<'a, 'b>, EnumerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let (path, archived) = self.dirs.pop()?;
+                match fs::read_dir(&path) {
+                    Ok(dir) => self.current = Some((dir, archived)),
+                    // a storage doesn't need an archive
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                        continue,
+                    Err(e) =>
+                        return Some(Err(EnumerateError::ReadDir(path, e))),
+                }
+            }
+
+            let (entry, archived) = {
+                let (dir, archived) = self.current.as_mut()
+                    .expect("Internal error, no current dir");
+                (dir.next(), *archived)
+            };
 
-        let mut nodes = Vec::new();
-        for entry in dir {
             let entry = match entry {
-                Ok(a) => a,
-                Err(e) => {
-                    println!("Invalid nodes entry in storage {}: {}",
-                        self.name, e);
+                Some(Ok(a)) => a.path(),
+                Some(Err(e)) => return Some(Err(EnumerateError::Entry(e))),
+                None => {
+                    self.current = None;
                     continue;
                 },
             };
 
-            let entry = entry.path();
-            if entry.is_dir() {
+            // hidden and temporary files (e.g. from file::write_atomic)
+            // are no nodes
+            let hidden = entry.file_name().and_then(|f| f.to_str())
+                .is_some_and(|f| f.starts_with('.') || f.ends_with(".tmp"));
+            if hidden || entry.is_dir() {
                 continue;
             }
 
             let id = entry.file_stem()
                 .and_then(|f| f.to_str())
                 .and_then(|f| f.parse::<u64>().ok());
+            return Some(match id {
+                Some(id) => Ok(Node::new_archived(self.storage, id, archived)),
+                None => Err(EnumerateError::InvalidName(entry)),
+            });
+        }
+    }
+}
+
+impl<'a, 'b, 'c> Iterator for Matching<'a, 'b, 'c> {
+    type Item = Result<Node<'a, 'b>, EnumerateError>;
 
-            match id {
-                Some(id) => nodes.push(Node::new_archived(&self, id, archived)),
-                None => println!("Invalid node file in {}: {}",
-                    self.name, entry.to_str().unwrap_or("<invalid>")),
+    fn next(&mut self) -> Option<Self::Item> {
+        for node in &mut self.nodes {
+            let node = match node {
+                Ok(a) => a,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let matches = {
+                let meta_node = pattern::MetaNode::new(&node);
+                pattern::node_matches(&meta_node, self.cond)
+            };
+
+            if matches {
+                return Some(Ok(node));
             }
         }
 
-        nodes
+        None
     }
 }
 
-/// RAII drop implementation to save the storages state.
-impl<'a> Drop for Storage<'a> {
-    fn drop(&mut self) {
-        let mut path = self.path.clone();
-        path.push("storage");
-        let mut f = match File::create(path) {
-            Ok(f) => f,
-            Err(err) => {
-                println!("Failed to create file to write \
-                    storage state: {}", err);
-                return;
+impl<'a, 'b> NextNode<'a, 'b> {
+    /// Returns the reserved id.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the node that will be created. Its files can be written
+    /// directly (e.g. by an editor) instead of using write_content
+    /// and write_meta.
+    pub fn node(&self) -> Node<'a, 'b> {
+        Node::new_archived(self.storage, self.id, false)
+    }
+
+    /// Writes the content of the node.
+    pub fn write_content(&self, content: &[u8]) -> Result<(), Error> {
+        self.node().write_content(content)
+    }
+
+    /// Writes the meta file of the node.
+    pub fn write_meta(&self, meta: &toml::Value) -> Result<(), Error> {
+        let path = self.node().meta_path();
+        <toml::Value as toml::ValueImpl>::save(meta, &path)
+            .map_err(|cause| Error::NodeFile { id: self.id, path, cause })
+    }
+
+    /// Commits the node, i.e. keeps it. Fails with Error::Incomplete if
+    /// content or meta file were not written, the node is rolled back
+    /// in this case.
+    /// Records the node as not archived in its meta file if the meta
+    /// file doesn't say anything about it, see Node::archive.
+    pub fn commit(mut self) -> Result<Node<'a, 'b>, Error> {
+        let mut node = self.node();
+        for path in &[node.node_path(), node.meta_path()] {
+            if !path.is_file() {
+                return Err(Error::Incomplete {
+                    id: self.id,
+                    path: path.clone(),
+                });
             }
-        };
+        }
+
+        let recorded = node.meta().ok()
+            .and_then(|meta| meta.get("archived")).is_some();
+        if !recorded {
+            node.patch_meta(&[("archived", Some(false.into()))])?;
+        }
+
+        self.committed = true;
+        Ok(node)
+    }
+}
 
-        if let Err(err) = f.write_all(toml::to_string(&self.state)
-                .expect("Internal error, deserializing state file")
-                .as_bytes()) {
-            println!("Failed to write initial config file: {}", err);
+/// Rolls back uncommitted nodes.
+impl<'a, 'b> Drop for NextNode<'a, 'b> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        // the files might not have been written at all
+        let node = self.node();
+        let _ = fs::remove_file(node.node_path());
+        let _ = fs::remove_file(node.meta_path());
+        if let Err(err) = self.storage.release_id(self.id) {
+            warn!("{}", err);
+        }
+    }
+}
+
+// Reads the state file of the storage at the given path.
+fn read_state(path: &Path) -> Result<StorageState, LoadStorageError> {
+    let mut f = match File::open(path.join("storage")) {
+        Ok(f) => f,
+        Err(e) => return Err(LoadStorageError::Open(e)),
+    };
+
+    let mut s = String::new();
+    if let Err(e) = f.read_to_string(&mut s) {
+        return Err(LoadStorageError::Read(e));
+    }
+
+    toml::from_str::<StorageState>(&s).map_err(LoadStorageError::Parse)
+}
+
+// Atomically replaces the state file of the storage at the given path.
+fn write_state(path: &Path, state: &StorageState) -> io::Result<()> {
+    let state = toml::to_string(state)
+        .expect("Internal error, serializing state file");
+    file::write_atomic(path.join("storage"), state.as_bytes())
+}
+
+// Turns an error from reading the state file of an already loaded
+// storage into an io error.
+fn state_io_error(err: LoadStorageError) -> io::Error {
+    match err {
+        LoadStorageError::Open(e) | LoadStorageError::Read(e) => e,
+        LoadStorageError::Parse(e) =>
+            io::Error::new(io::ErrorKind::InvalidData, e),
+        err => io::Error::other(err),
+    }
+}
+
+impl Drop for StorageLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// RAII drop implementation to save the storages state if it wasn't
+/// saved explicitly, see Storage::save.
+impl<'a> Drop for Storage<'a> {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            warn!("{}", err);
         }
     }
 }
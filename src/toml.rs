@@ -1,8 +1,10 @@
 extern crate toml;
 
 pub use self::toml::*;
+use super::file;
 
 use std::path::Path;
+use std::ops::Range;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
@@ -19,6 +21,7 @@ pub trait ValueImpl {
     fn load<P: AsRef<Path>>(p: P) -> Result<Value, LoadError>;
     fn save<P: AsRef<Path>>(&self, p: P) -> io::Result<()>;
     fn find(&self, name: &str) -> Option<&Value>;
+    fn find_all(&self, name: &str) -> Vec<&Value>;
     fn find_mut(&mut self, name: &str) -> Option<&mut Value>;
     fn set<V: Into<Value>>(&mut self, name: &str, v: V) -> bool;
 }
@@ -52,13 +55,17 @@ impl ValueImpl for Value {
         // toml values with Value
         let s = toml::ser::to_string_pretty(&self)
             .expect("Failed to transform toml to string");
-        File::create(p)?.write_all(s.as_bytes())
+        file::write_atomic(p, s.as_bytes())
     }
 
     fn find(&self, name: &str) -> Option<&Value> {
         toml_find(self, name)
     }
 
+    fn find_all(&self, name: &str) -> Vec<&Value> {
+        toml_find_all(self, name)
+    }
+
     fn find_mut(&mut self, name: &str) -> Option<&mut Value> {
         toml_find_mut(self, name)
     }
@@ -68,68 +75,380 @@ impl ValueImpl for Value {
     }
 }
 
-// TODO: allow array access in name. Like foo.bar.arr:3
+/// Sets (or removes, for None) the given top-level entries in the given
+/// toml document. Only the lines of these entries are changed, the
+/// comments and order of the others are kept. If an entry can't be
+/// changed in place, the whole document is formatted again instead.
+/// Returns the new document and its value.
+pub fn patch(doc: &str, entries: &[(&str, Option<Value>)])
+        -> Result<(String, Value), de::Error> {
+    let mut value = doc.parse::<Value>()?;
+    let mut lines = doc.lines().map(str::to_string).collect::<Vec<_>>();
+    for &(key, ref entry) in entries {
+        let table = value.as_table_mut().expect("Toml document is no table");
+        let (range, end) = find_entry(&lines, key);
+        let entry = match *entry {
+            Some(ref a) => a,
+            None => {
+                table.remove(key);
+                if let Some(range) = range {
+                    lines.drain(range);
+                }
+                continue;
+            },
+        };
+
+        table.insert(key.to_string(), entry.clone());
+        let mut single = value::Table::new();
+        single.insert(key.to_string(), entry.clone());
+        let line = match to_string(&single) {
+            Ok(a) => a.trim_end().to_string(),
+            Err(_) => continue, // checked below
+        };
+
+        match range {
+            Some(range) => {
+                lines.splice(range, Some(line));
+            },
+            None => {
+                // insert it after the last top-level entry, before the
+                // comments of the first table
+                let mut at = end;
+                while at > 0 && (lines[at - 1].trim().is_empty() ||
+                        lines[at - 1].trim_start().starts_with('#')) {
+                    at -= 1;
+                }
+                lines.insert(at, line);
+            },
+        }
+    }
+
+    let mut patched = lines.join("\n");
+    patched.push('\n');
+    if patched.parse::<Value>().ok().as_ref() == Some(&value) {
+        return Ok((patched, value));
+    }
+
+    let formatted = ser::to_string_pretty(&value)
+        .expect("Failed to transform toml to string");
+    Ok((formatted, value))
+}
+
+// Returns the lines of the top-level entry with the given key (if there
+// is one) and the line of the first table header.
+fn find_entry(lines: &[String], key: &str)
+        -> (Option<Range<usize>>, usize) {
+    let mut found = None;
+    let mut i = 0;
+    while i < lines.len() && !lines[i].trim_start().starts_with('[') {
+        let k = entry_key(&lines[i]);
+        let end = match k {
+            // a value (e.g. an array or string) may span multiple lines,
+            // it ends with the first line that completes it
+            Some(_) => (i + 1..lines.len() + 1)
+                .find(|&e| lines[i..e].join("\n").parse::<Value>().is_ok())
+                .unwrap_or(i + 1),
+            None => i + 1,
+        };
+
+        if k == Some(key) {
+            found = Some(i..end);
+        }
+        i = end;
+    }
+
+    (found, i)
+}
+
+// Returns the key of the entry defined in the given line, if any.
+fn entry_key(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    let (key, rest) = match line.chars().next() {
+        Some(q @ '"') | Some(q @ '\'') => {
+            let end = line[1..].find(q)? + 1;
+            (&line[1..end], &line[end + 1..])
+        },
+        _ => {
+            let end = line.find('=')?;
+            (line[..end].trim_end(), &line[end..])
+        },
+    };
+
+    if !key.is_empty() && rest.trim_start().starts_with('=') {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+// Resolves an array index as used in names. Negative indices
+// count from the end, i.e. -1 is the last element.
+fn array_index(part: &str, len: usize) -> Option<usize> {
+    let i = part.parse::<i64>().ok()?;
+    let i = if i < 0 { len as i64 + i } else { i };
+    if i < 0 || i >= len as i64 { None } else { Some(i as usize) }
+}
 
 /// Returns the value with the given name, if existent.
-/// Can access sub tables, like foo.bar.val
+/// Can access sub tables, like foo.bar.val, and array elements by index,
+/// like foo.links.3 or foo.links.-1 for the last one.
+/// A '*' matches all elements of an array (or all entries of a table),
+/// like tasks.*.done. In that case the first matching value is returned,
+/// see toml_find_all.
 pub fn toml_find<'a>(v: &'a Value, name: &str)
         -> Option<&'a Value> {
-    let mut next = v;
+    toml_find_all(v, name).into_iter().next()
+}
+
+/// Returns all values matching the given name.
+/// Names with a '*' can match multiple values, see toml_find.
+pub fn toml_find_all<'a>(v: &'a Value, name: &str) -> Vec<&'a Value> {
+    let mut cur = vec!(v);
     for part in name.split('.') {
-        let cur = next;
-        match *cur {
-            Value::Table(ref table) =>
-                match table.get(part) {
-                    Some(entry) => next = entry,
-                    None => return None,
-            }, _ => return None,
-        };
+        let mut next = Vec::new();
+        for v in cur {
+            match *v {
+                Value::Table(ref table) if part == "*" =>
+                    next.extend(table.values()),
+                Value::Array(ref array) if part == "*" =>
+                    next.extend(array.iter()),
+                Value::Table(ref table) => next.extend(table.get(part)),
+                Value::Array(ref array) => next.extend(
+                    array_index(part, array.len()).map(|i| &array[i])),
+                _ => (),
+            }
+        }
+
+        cur = next;
     }
-    Some(next)
+
+    cur
 }
 
+/// Like toml_find, returns the first matching value.
 pub fn toml_find_mut<'a>(v: &'a mut Value, name: &str)
         -> Option<&'a mut Value> {
-    let mut next = v;
-    for part in name.split('.') {
-        let cur = next;
-        match *cur {
-            Value::Table(ref mut table) =>
-                match table.get_mut(part) {
-                    Some(entry) => next = entry,
-                    None => return None,
-            }, _ => return None,
-        };
-    }
-    Some(next)
+    let mut it = name.splitn(2, '.');
+    let part = it.next().expect("Invalid name given");
+    let rest = it.next();
+    let children: Vec<&'a mut Value> = match (v, part) {
+        (&mut Value::Table(ref mut table), "*") => table.values_mut().collect(),
+        (&mut Value::Array(ref mut array), "*") => array.iter_mut().collect(),
+        (&mut Value::Table(ref mut table), _) =>
+            table.get_mut(part).into_iter().collect(),
+        (&mut Value::Array(ref mut array), _) => {
+            let len = array.len();
+            array_index(part, len).map(move |i| &mut array[i])
+                .into_iter().collect()
+        }, _ => return None,
+    };
+
+    let rest = match rest {
+        Some(rest) => rest,
+        None => return children.into_iter().next(),
+    };
+
+    children.into_iter().filter_map(|c| toml_find_mut(c, rest)).next()
 }
 
 /// Returns false if name cannot be inserted.
 /// E.g. when name is "foo.bar" but "foo" is already a (non-table)
-/// value.
+/// value. Missing tables are created.
+/// Array elements can be addressed by (possibly negative) index,
+/// the index one past the last element appends to the array.
+/// With a '*', the value is set for all matching elements, e.g.
+/// tasks.*.done, in which case it returns false if any of them failed.
 pub fn toml_set(v: &mut Value, name: &str, val: Value) -> bool {
-    let mut next = v;
-    let mut it = name.split('.');
-    let last = it.next_back().expect("Invalid name given");
-
-    // make sure all sub tables exist, create them if needed
-    for part in it {
-        let cur = next;
-        match *cur {
-            Value::Table(ref mut table) => {
-                let e = table.entry(part.to_string());
-                next = e.or_insert(Value::new());
-            },
-            _ => return false,
-        };
+    let mut it = name.splitn(2, '.');
+    let part = it.next().expect("Invalid name given");
+    let rest = match it.next() {
+        Some(rest) => rest,
+        None => return set_entry(v, part, val),
+    };
+
+    match (v, part) {
+        (&mut Value::Table(ref mut table), "*") =>
+            set_all(table.values_mut(), rest, &val),
+        (&mut Value::Array(ref mut array), "*") =>
+            set_all(array.iter_mut(), rest, &val),
+        (&mut Value::Table(ref mut table), _) => {
+            // make sure all sub tables exist, create them if needed
+            let next = table.entry(part.to_string()).or_insert(Value::new());
+            toml_set(next, rest, val)
+        }, (&mut Value::Array(ref mut array), _) => {
+            match array_index(part, array.len()) {
+                Some(i) => toml_set(&mut array[i], rest, val),
+                None => false,
+            }
+        }, _ => false,
     }
+}
+
+// Sets name to val in all given values, see toml_set.
+fn set_all<'a, I>(values: I, name: &str, val: &Value) -> bool
+        where I: Iterator<Item = &'a mut Value> {
+    let mut ok = true;
+    for v in values {
+        ok &= toml_set(v, name, val.clone());
+    }
+    ok
+}
 
-    // insert
-    match *next {
-        Value::Table(ref mut table) =>
-            table.insert(last.to_string(), val),
-        _ => return false,
+// Sets the direct entry part of v to val.
+fn set_entry(v: &mut Value, part: &str, val: Value) -> bool {
+    match (v, part) {
+        (&mut Value::Table(ref mut table), "*") => {
+            for entry in table.values_mut() {
+                *entry = val.clone();
+            }
+        }, (&mut Value::Array(ref mut array), "*") => {
+            for entry in array.iter_mut() {
+                *entry = val.clone();
+            }
+        }, (&mut Value::Table(ref mut table), _) => {
+            table.insert(part.to_string(), val);
+        }, (&mut Value::Array(ref mut array), _) => {
+            if part == array.len().to_string() {
+                array.push(val);
+            } else {
+                match array_index(part, array.len()) {
+                    Some(i) => array[i] = val,
+                    None => return false,
+                }
+            }
+        }, _ => return false,
     };
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Value {
+        "links = ['a', 'b', 'c', 'd']\n\
+         [[tasks]]\nname = 'x'\ndone = true\n\
+         [[tasks]]\nname = 'y'\ndone = false\n\
+         [[tasks]]\nname = 'z'\n"
+            .parse().expect("Invalid test document")
+    }
+
+    #[test]
+    fn find_index() {
+        let doc = doc();
+        assert_eq!(toml_find(&doc, "links.3"), Some(&Value::from("d")));
+        assert_eq!(toml_find(&doc, "links.0"), Some(&Value::from("a")));
+        assert_eq!(toml_find(&doc, "links.-1"), Some(&Value::from("d")));
+        assert_eq!(toml_find(&doc, "links.-4"), Some(&Value::from("a")));
+        assert_eq!(toml_find(&doc, "links.4"), None);
+        assert_eq!(toml_find(&doc, "links.-5"), None);
+        assert_eq!(toml_find(&doc, "tasks.-1.name"), Some(&Value::from("z")));
+    }
+
+    #[test]
+    fn find_wildcard() {
+        let doc = doc();
+        assert_eq!(toml_find_all(&doc, "tasks.*.done"),
+            vec!(&Value::from(true), &Value::from(false)));
+        assert_eq!(toml_find(&doc, "tasks.*.done"), Some(&Value::from(true)));
+        assert_eq!(toml_find_all(&doc, "links.*").len(), 4);
+        assert!(toml_find_all(&doc, "tasks.*.missing").is_empty());
+    }
+
+    #[test]
+    fn find_mut() {
+        let mut doc = doc();
+        *toml_find_mut(&mut doc, "links.-1").unwrap() = Value::from("e");
+        assert_eq!(toml_find(&doc, "links.3"), Some(&Value::from("e")));
+
+        // the first element that has the field
+        *toml_find_mut(&mut doc, "tasks.*.done").unwrap() = Value::from(1);
+        assert_eq!(toml_find(&doc, "tasks.0.done"), Some(&Value::from(1)));
+        assert!(toml_find_mut(&mut doc, "links.4").is_none());
+    }
+
+    #[test]
+    fn set_wildcard() {
+        let mut doc = doc();
+        assert!(toml_set(&mut doc, "tasks.*.done", Value::from(true)));
+        assert_eq!(toml_find_all(&doc, "tasks.*.done"),
+            vec!(&Value::from(true); 3));
+
+        assert!(toml_set(&mut doc, "links.*", Value::from("x")));
+        assert_eq!(toml_find_all(&doc, "links.*"), vec!(&Value::from("x"); 4));
+
+        // fails for elements that aren't tables
+        assert!(!toml_set(&mut doc, "links.*.a", Value::from(1)));
+    }
+
+    #[test]
+    fn set_index() {
+        let mut doc = doc();
+        assert!(toml_set(&mut doc, "links.-1", Value::from("e")));
+        assert_eq!(toml_find(&doc, "links.3"), Some(&Value::from("e")));
+
+        // the index one past the end appends
+        assert!(toml_set(&mut doc, "links.4", Value::from("f")));
+        assert_eq!(toml_find(&doc, "links.-1"), Some(&Value::from("f")));
+        assert_eq!(toml_find_all(&doc, "links.*").len(), 5);
+        assert!(!toml_set(&mut doc, "links.6", Value::from("g")));
+
+        assert!(toml_set(&mut doc, "tasks.2.done", Value::from(false)));
+        assert_eq!(toml_find(&doc, "tasks.2.done"), Some(&Value::from(false)));
+        assert!(!toml_set(&mut doc, "tasks.3.done", Value::from(false)));
+    }
+
+    fn patched(doc: &str, entries: &[(&str, Option<Value>)]) -> String {
+        let (text, value) = patch(doc, entries).expect("Invalid test document");
+        assert_eq!(text.parse::<Value>().ok(), Some(value));
+        text
+    }
+
+    #[test]
+    fn patch_multiline_array() {
+        let doc = "# tags\ntags = [\n    \"a\",\n    \"b\",\n]\n\
+                   # count\ncount = 1\n";
+        assert_eq!(patched(doc, &[("count", Some(Value::from(2)))]),
+            "# tags\ntags = [\n    \"a\",\n    \"b\",\n]\n\
+             # count\ncount = 2\n");
+        assert_eq!(patched(doc, &[("tags", Some(Value::from(3)))]),
+            "# tags\ntags = 3\n# count\ncount = 1\n");
+        assert_eq!(patched(doc, &[("tags", None)]),
+            "# tags\n# count\ncount = 1\n");
+    }
+
+    #[test]
+    fn patch_trailing_table() {
+        let doc = "a = 1\n\n# table\n[table]\nb = 2\n";
+        assert_eq!(patched(doc, &[("b", Some(Value::from(3)))]),
+            "a = 1\nb = 3\n\n# table\n[table]\nb = 2\n");
+        assert_eq!(patched(doc, &[("a", None)]),
+            "\n# table\n[table]\nb = 2\n");
+
+        let doc = "[table]\nb = 2\n";
+        assert_eq!(patched(doc, &[("b", Some(Value::from(3)))]),
+            "b = 3\n[table]\nb = 2\n");
+    }
+
+    #[test]
+    fn patch_quoted_keys() {
+        let doc = "\"a b\" = 1\n'c=d' = 2\ne = 3\n";
+        assert_eq!(patched(doc, &[
+                ("a b", Some(Value::from(4))),
+                ("c=d", None),
+                ("e", Some(Value::from(5)))]),
+            "\"a b\" = 4\ne = 5\n");
+    }
+
+    #[test]
+    fn patch_fallback() {
+        // escaped quotes in keys aren't recognized, the entry would be
+        // inserted a second time
+        let doc = "# comment\n\"a\\\"b\" = 1\n";
+        let text = patched(doc, &[("a\"b", Some(Value::from(2)))]);
+        assert!(!text.contains("# comment"));
+        assert_eq!(toml_find(&text.parse().unwrap(), "a\"b"),
+            Some(&Value::from(2)));
+    }
+}